@@ -0,0 +1,53 @@
+use super::{config_path, FileSystemError};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub(crate) enum ConfigError {
+    #[error("{0}")]
+    FileSystem(FileSystemError),
+    #[error("{0}")]
+    IO(std::io::Error),
+    #[error("{0}: {1}")]
+    Parse(String, toml::de::Error),
+}
+
+/// User settings read from `~/.config/llvmgr/config.toml`.
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Config {
+    /// Appended to every cmake configure step, before the ones passed with --cmake-arg
+    #[serde(default)]
+    pub cmake_args: Vec<String>,
+    /// Tried in order before the urls of the release manifest
+    #[serde(default)]
+    pub mirrors: Vec<String>,
+    /// How files are laid out on the mirrors
+    pub mirror_template: Option<String>,
+    /// PEM file of certificate authorities trusted besides the system ones
+    pub ca_bundle: Option<PathBuf>,
+    /// Proxy of every download, instead of HTTP_PROXY, HTTPS_PROXY and ALL_PROXY
+    pub proxy: Option<String>,
+    /// Comma separated hosts reached without the proxy
+    pub no_proxy: Option<String>,
+    /// Range requests each large download is split into
+    pub download_connections: Option<usize>,
+}
+
+impl Config {
+    pub fn load() -> Result<Config, ConfigError> {
+        let path = config_path("config.toml").map_err(ConfigError::FileSystem)?;
+        Config::read(&path)
+    }
+
+    /// Reads `path`, or the defaults when it does not exist.
+    pub fn read(path: &Path) -> Result<Config, ConfigError> {
+        if !path.exists() {
+            return Ok(Config::default());
+        }
+
+        let s = std::fs::read_to_string(path).map_err(ConfigError::IO)?;
+        toml::from_str(&s).map_err(|err| ConfigError::Parse(path.display().to_string(), err))
+    }
+}
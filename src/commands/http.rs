@@ -0,0 +1,508 @@
+use super::{
+    config::{Config, ConfigError},
+    FileSystemError, Mirrors,
+};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+use thiserror::Error;
+
+/// Connections of each download of a large file, unless configured
+const DEFAULT_DOWNLOAD_CONNECTIONS: usize = 4;
+
+/// Hosts `GITHUB_TOKEN` is sent to
+const GITHUB_HOSTS: &[&str] = &["github.com", "api.github.com"];
+
+const CERTIFICATE_BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+const CERTIFICATE_END: &str = "-----END CERTIFICATE-----";
+
+static HTTP_CLIENT: OnceLock<HttpClient> = OnceLock::new();
+
+#[derive(Error, Debug)]
+pub(crate) enum HttpClientError {
+    #[error("{0}")]
+    Config(ConfigError),
+    #[error("{0}")]
+    FileSystem(FileSystemError),
+    #[error("{0}: {1}")]
+    IO(String, std::io::Error),
+    #[error("{0}: {1}")]
+    Certificate(String, reqwest::Error),
+    #[error("{0} has no certificates")]
+    NoCertificates(String),
+    #[error("proxy {0}: {1}")]
+    Proxy(String, reqwest::Error),
+    #[error("{0}")]
+    Reqwest(reqwest::Error),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Credentials {
+    Bearer(String),
+    Basic(String, String),
+}
+
+/// The client shared by every download, with the credentials of each host.
+pub(crate) struct HttpClient {
+    client: reqwest::Client,
+    credentials: HashMap<String, Credentials>,
+    connections: usize,
+}
+
+impl HttpClient {
+    fn new(config: &Config) -> Result<HttpClient, HttpClientError> {
+        let mut builder =
+            reqwest::Client::builder().user_agent(concat!("llvmgr/", env!("CARGO_PKG_VERSION")));
+
+        if let Some(path) = config.ca_bundle.as_ref() {
+            for certificate in read_ca_bundle(path)? {
+                builder = builder.add_root_certificate(certificate);
+            }
+        }
+
+        // Without a proxy in the configuration, HTTP_PROXY, HTTPS_PROXY and ALL_PROXY are used
+        if let Some(url) = config.proxy.as_ref() {
+            let proxy = reqwest::Proxy::all(url)
+                .map_err(|err| HttpClientError::Proxy(url.clone(), err))?
+                .no_proxy(
+                    config
+                        .no_proxy
+                        .as_deref()
+                        .and_then(reqwest::NoProxy::from_string),
+                );
+            builder = builder.proxy(proxy);
+        }
+
+        let client = builder.build().map_err(HttpClientError::Reqwest)?;
+
+        let netrc = match netrc_path()? {
+            Some(path) => Some(
+                std::fs::read_to_string(&path)
+                    .map_err(|err| HttpClientError::IO(path.display().to_string(), err))?,
+            ),
+            None => None,
+        };
+        let mirrors = Mirrors::new(&config.mirrors, config.mirror_template.as_deref());
+        let credentials = host_credentials(
+            netrc.as_deref(),
+            env_token("GITHUB_TOKEN"),
+            env_token("LLVMGR_TOKEN"),
+            &mirrors.hosts(),
+        );
+
+        Ok(HttpClient {
+            client,
+            credentials,
+            connections: config
+                .download_connections
+                .unwrap_or(DEFAULT_DOWNLOAD_CONNECTIONS)
+                .max(1),
+        })
+    }
+
+    /// Range requests a large file is downloaded with at once, when the server accepts them
+    pub fn connections(&self) -> usize {
+        self.connections
+    }
+
+    pub fn get(&self, url: &str) -> reqwest::RequestBuilder {
+        let req = self.client.get(url);
+
+        let host = reqwest::Url::parse(url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_string));
+        match host.and_then(|host| self.credentials.get(&host)) {
+            Some(Credentials::Bearer(token)) => req.bearer_auth(token),
+            Some(Credentials::Basic(login, password)) => req.basic_auth(login, Some(password)),
+            None => req,
+        }
+    }
+}
+
+/// The client shared by every download, configured by `~/.config/llvmgr/config.toml`.
+pub(crate) fn http_client() -> Result<&'static HttpClient, HttpClientError> {
+    if let Some(client) = HTTP_CLIENT.get() {
+        return Ok(client);
+    }
+
+    let config = Config::load().map_err(HttpClientError::Config)?;
+    init_http_client(&config)
+}
+
+/// Creates the shared client with `config`, unless it was created before.
+pub(crate) fn init_http_client(config: &Config) -> Result<&'static HttpClient, HttpClientError> {
+    if let Some(client) = HTTP_CLIENT.get() {
+        return Ok(client);
+    }
+
+    let client = HttpClient::new(config)?;
+    Ok(HTTP_CLIENT.get_or_init(|| client))
+}
+
+/// Credentials of each host.
+///
+/// Tokens are only sent to the hosts they are meant for, and take precedence over
+/// `.netrc`. Its `default` entry is only used for the mirrors, so it is not sent to
+/// hosts that did not ask for it.
+fn host_credentials(
+    netrc: Option<&str>,
+    github_token: Option<String>,
+    llvmgr_token: Option<String>,
+    mirror_hosts: &[String],
+) -> HashMap<String, Credentials> {
+    let (mut credentials, default) = netrc.map(parse_netrc).unwrap_or_default();
+
+    if let Some(token) = github_token {
+        for host in GITHUB_HOSTS {
+            credentials.insert(host.to_string(), Credentials::Bearer(token.clone()));
+        }
+    }
+    for host in mirror_hosts {
+        let mirror_credentials = match (&llvmgr_token, &default) {
+            (Some(token), _) => Credentials::Bearer(token.clone()),
+            (None, Some(default)) if !credentials.contains_key(host) => default.clone(),
+            _ => continue,
+        };
+        credentials.insert(host.clone(), mirror_credentials);
+    }
+
+    credentials
+}
+
+fn env_token(name: &str) -> Option<String> {
+    std::env::var(name)
+        .ok()
+        .map(|token| token.trim().to_string())
+        .filter(|token| !token.is_empty())
+}
+
+fn read_ca_bundle(path: &Path) -> Result<Vec<reqwest::Certificate>, HttpClientError> {
+    let name = path.display().to_string();
+    let pem =
+        std::fs::read_to_string(path).map_err(|err| HttpClientError::IO(name.clone(), err))?;
+
+    let mut certificates = vec![];
+    let mut s = pem.as_str();
+    while let Some(start) = s.find(CERTIFICATE_BEGIN) {
+        let Some(end) = s[start..].find(CERTIFICATE_END) else {
+            break;
+        };
+        let end = start + end + CERTIFICATE_END.len();
+        let certificate = reqwest::Certificate::from_pem(&s.as_bytes()[start..end])
+            .map_err(|err| HttpClientError::Certificate(name.clone(), err))?;
+        certificates.push(certificate);
+        s = &s[end..];
+    }
+
+    if certificates.is_empty() {
+        return Err(HttpClientError::NoCertificates(name));
+    }
+    Ok(certificates)
+}
+
+/// `NETRC`, or `~/.netrc` when it exists.
+fn netrc_path() -> Result<Option<PathBuf>, HttpClientError> {
+    if let Some(path) = std::env::var_os("NETRC") {
+        return Ok(Some(path.into()));
+    }
+
+    let dirs = directories::UserDirs::new()
+        .ok_or(HttpClientError::FileSystem(FileSystemError::UserDirError))?;
+    let name = if cfg!(windows) { "_netrc" } else { ".netrc" };
+    let path = dirs.home_dir().join(name);
+    Ok(Some(path).filter(|path| path.exists()))
+}
+
+// splits lines like: machine example.com password "two words"
+fn netrc_tokens(line: &str) -> Vec<String> {
+    let mut tokens = vec![];
+    let mut chars = line.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+
+        let mut token = String::new();
+        if c == '"' {
+            // Quoted tokens may have spaces, and escape quotes with `\`
+            chars.next();
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => break,
+                    '\\' => token.extend(chars.next()),
+                    c => token.push(c),
+                }
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                token.push(c);
+            }
+        }
+        tokens.push(token);
+    }
+    tokens
+}
+
+// parses files like:
+// machine artifacts.example.com login ci password secret
+// default login anonymous password guest
+fn parse_netrc(s: &str) -> (HashMap<String, Credentials>, Option<Credentials>) {
+    // Macros run from the first `macdef` until an empty line
+    let mut tokens = vec![];
+    let mut in_macdef = false;
+    for line in s.lines() {
+        if in_macdef {
+            in_macdef = !line.trim().is_empty();
+            continue;
+        }
+        if line.trim_start().starts_with('#') {
+            continue;
+        }
+        for token in netrc_tokens(line) {
+            if token == "macdef" {
+                in_macdef = true;
+                break;
+            }
+            tokens.push(token);
+        }
+    }
+
+    let mut credentials = HashMap::new();
+    let mut default = None;
+
+    // `None` is the default entry
+    let mut entries: Vec<(Option<&str>, Option<&str>, Option<&str>)> = vec![];
+    let mut tokens = tokens.iter().map(String::as_str);
+    while let Some(token) = tokens.next() {
+        match token {
+            "machine" => {
+                if let Some(machine) = tokens.next() {
+                    entries.push((Some(machine), None, None));
+                }
+            }
+            "default" => entries.push((None, None, None)),
+            "login" => {
+                if let Some(entry) = entries.last_mut() {
+                    entry.1 = tokens.next();
+                }
+            }
+            "password" => {
+                if let Some(entry) = entries.last_mut() {
+                    entry.2 = tokens.next();
+                }
+            }
+            "account" => {
+                tokens.next();
+            }
+            _ => {}
+        }
+    }
+
+    // The first entry of a machine wins
+    for (machine, login, password) in entries {
+        let Some(password) = password else {
+            continue;
+        };
+        let entry = Credentials::Basic(login.unwrap_or_default().into(), password.into());
+        match machine {
+            Some(machine) => {
+                credentials.entry(machine.to_string()).or_insert(entry);
+            }
+            None => {
+                default.get_or_insert(entry);
+            }
+        }
+    }
+
+    (credentials, default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{host_credentials, netrc_tokens, parse_netrc, Credentials, HttpClient};
+    use crate::commands::{
+        config::Config,
+        http::HttpClientError,
+        test_server::{Response, TestServer},
+    };
+    use std::path::{Path, PathBuf};
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("src/commands/fixtures")
+            .join(name)
+    }
+
+    fn basic(login: &str, password: &str) -> Credentials {
+        Credentials::Basic(login.into(), password.into())
+    }
+
+    #[test]
+    fn splits_quoted_tokens() {
+        assert_eq!(
+            netrc_tokens(r#"machine a.com  password "two words" login "\"q\"\\""#),
+            [
+                "machine",
+                "a.com",
+                "password",
+                "two words",
+                "login",
+                r#""q"\"#
+            ]
+        );
+        assert_eq!(netrc_tokens(r#"password """#), ["password", ""]);
+    }
+
+    #[test]
+    fn parses_netrc() {
+        let (credentials, default) = parse_netrc(
+            "# comment with machine evil.com password x
+machine a.com login alice password secret
+machine b.com
+  login bob
+  password \"b c\"
+  account ignored
+
+macdef init
+machine c.com login mallory password macro
+
+machine a.com login second password ignored
+machine no-password.com login carol
+default login anonymous password guest
+",
+        );
+
+        assert_eq!(credentials.get("a.com"), Some(&basic("alice", "secret")));
+        assert_eq!(credentials.get("b.com"), Some(&basic("bob", "b c")));
+        assert_eq!(credentials.get("c.com"), None);
+        assert_eq!(credentials.get("evil.com"), None);
+        assert_eq!(credentials.get("no-password.com"), None);
+        assert_eq!(default, Some(basic("anonymous", "guest")));
+    }
+
+    #[test]
+    fn tokens_take_precedence_over_netrc() {
+        let netrc = "machine github.com login user password netrc
+machine mirror.example.com login user password netrc
+default login anonymous password guest";
+        let mirrors = ["mirror.example.com".to_string()];
+
+        let credentials = host_credentials(
+            Some(netrc),
+            Some("github".into()),
+            Some("llvmgr".into()),
+            &mirrors,
+        );
+        assert_eq!(
+            credentials.get("github.com"),
+            Some(&Credentials::Bearer("github".into()))
+        );
+        assert_eq!(
+            credentials.get("api.github.com"),
+            Some(&Credentials::Bearer("github".into()))
+        );
+        assert_eq!(
+            credentials.get("mirror.example.com"),
+            Some(&Credentials::Bearer("llvmgr".into()))
+        );
+
+        let credentials = host_credentials(Some(netrc), None, None, &mirrors);
+        assert_eq!(credentials.get("github.com"), Some(&basic("user", "netrc")));
+        assert_eq!(
+            credentials.get("mirror.example.com"),
+            Some(&basic("user", "netrc"))
+        );
+        assert_eq!(credentials.get("api.github.com"), None);
+    }
+
+    #[test]
+    fn default_credentials_only_go_to_mirrors() {
+        let netrc = "default login anonymous password guest";
+        let mirrors = ["mirror.example.com".to_string()];
+
+        let credentials = host_credentials(Some(netrc), None, None, &mirrors);
+        assert_eq!(
+            credentials.get("mirror.example.com"),
+            Some(&basic("anonymous", "guest"))
+        );
+        assert_eq!(credentials.get("github.com"), None);
+        assert_eq!(credentials.len(), 1);
+    }
+
+    #[test]
+    fn reads_config() {
+        let dir = std::env::temp_dir().join(format!("llvmgr-{}-config", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        assert!(Config::read(&path).unwrap().proxy.is_none());
+
+        std::fs::write(
+            &path,
+            r#"
+mirrors = ["https://mirror.example.com/llvm"]
+ca_bundle = "/etc/ssl/company.pem"
+proxy = "http://proxy.example.com:3128"
+no_proxy = "localhost,.internal"
+download_connections = 8
+"#,
+        )
+        .unwrap();
+        let config = Config::read(&path).unwrap();
+        assert_eq!(config.mirrors, ["https://mirror.example.com/llvm"]);
+        assert_eq!(config.ca_bundle, Some("/etc/ssl/company.pem".into()));
+        assert_eq!(
+            config.proxy.as_deref(),
+            Some("http://proxy.example.com:3128")
+        );
+        assert_eq!(config.no_proxy.as_deref(), Some("localhost,.internal"));
+        assert_eq!(config.download_connections, Some(8));
+
+        std::fs::write(&path, "proxi = \"http://proxy.example.com\"").unwrap();
+        assert!(Config::read(&path).is_err());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn sends_requests_through_the_proxy() {
+        let proxy = TestServer::start(|_| Response::new(200).body(b"proxied")).await;
+        let config = Config {
+            proxy: Some(proxy.url.clone()),
+            download_connections: Some(2),
+            ..Default::default()
+        };
+        let client = HttpClient::new(&config).unwrap();
+        assert_eq!(client.connections(), 2);
+
+        let res = client
+            .get("http://llvm.example.com/file.tar.xz")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.bytes().await.unwrap().as_ref(), b"proxied");
+        let requests = proxy.requests();
+        assert_eq!(requests[0].path, "http://llvm.example.com/file.tar.xz");
+        assert_eq!(requests[0].header("host"), Some("llvm.example.com"));
+    }
+
+    #[test]
+    fn reads_ca_bundles() {
+        let config = Config {
+            ca_bundle: Some(fixture("ca-bundle.pem")),
+            ..Default::default()
+        };
+        assert!(HttpClient::new(&config).is_ok());
+
+        let config = Config {
+            ca_bundle: Some(fixture("../config.rs")),
+            ..Default::default()
+        };
+        assert!(matches!(
+            HttpClient::new(&config),
+            Err(HttpClientError::NoCertificates(_))
+        ));
+    }
+}
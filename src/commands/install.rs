@@ -1,15 +1,461 @@
-use super::llvm::{llvm_16, llvm_17, llvm_18};
+use super::{
+    config::Config,
+    host_llvm_target, list_installs,
+    llvm::{git_revision, llvm, release_index, GitRevision},
+    total_memory, CompilerCache, Mirrors, Profile, Version, VersionSpec,
+};
 use crate::{Args, InstallSubcommand};
-use color_eyre::eyre::Report;
+use color_eyre::{
+    eyre::{Report, WrapErr},
+    Help,
+};
+use std::{env::consts::EXE_SUFFIX, path::PathBuf};
+use thiserror::Error;
 
-#[derive(Debug)]
-pub(crate) enum InstallError {}
+#[derive(Error, Debug)]
+pub(crate) enum InstallError {
+    #[error("unknown tool {0}")]
+    UnknownTool(String),
+    #[error("{0}")]
+    InvalidVersion(String),
+    #[error("no release matches {0}")]
+    NoMatchingRelease(String),
+    #[error("{0} and {1} cannot be used together")]
+    ConflictingOptions(&'static str, &'static str),
+    #[error("{0} needs {1}")]
+    MissingOption(&'static str, &'static str),
+    #[error("{0} must be at least 1")]
+    ZeroJobs(&'static str),
+    #[error("missing version")]
+    MissingVersion,
+    #[error("{0} is not installed")]
+    UnknownToolchain(String),
+    #[error("{0} was installed without {1}")]
+    IncompleteToolchain(String, &'static str),
+    #[error("invalid sha256 {0}")]
+    InvalidSha256(String),
+}
+
+/// How to install a release, as asked in the command line.
+pub(crate) struct InstallOptions {
+    pub prebuilt: bool,
+    pub from_archive: Option<PathBuf>,
+    pub from_source: Option<PathBuf>,
+    pub git: Option<GitRevision>,
+    /// Appended to the cmake configure step
+    pub cmake_args: Vec<String>,
+    /// Reuse the options recorded by the previous install
+    pub rebuild: bool,
+    pub profile: Profile,
+    pub assertions: bool,
+    /// LLVM_ENABLE_PROJECTS, or the release defaults when `None`
+    pub projects: Option<Vec<String>>,
+    /// LLVM_ENABLE_RUNTIMES, or the release defaults when `None`
+    pub runtimes: Option<Vec<String>>,
+    /// LLVM_TARGETS_TO_BUILD, or all targets when `None`
+    pub targets: Option<Vec<String>>,
+    /// LLVM_EXPERIMENTAL_TARGETS_TO_BUILD
+    pub experimental_targets: Vec<String>,
+    /// Parallel compile jobs
+    pub jobs: usize,
+    /// Parallel link jobs, or unlimited when `None`
+    pub link_jobs: Option<usize>,
+    pub compiler_cache: CompilerCache,
+    /// Build clang and lld first, then build LLVM with them
+    pub bootstrap: bool,
+    /// `bin` folder of the install whose clang and lld build LLVM
+    pub host_toolchain: Option<PathBuf>,
+    /// Start from scratch instead of resuming an interrupted install
+    pub clean: bool,
+    /// Expected digest of the downloaded or local archive
+    pub sha256: Option<String>,
+    /// Fail unless archives are signed by a known release key
+    pub require_signature: bool,
+    pub mirrors: Mirrors,
+    /// Parallel downloads
+    pub download_jobs: usize,
+}
+
+/// `bin` folder of an installed version with clang and lld, found by folder name,
+/// like `17.0.6-asserts`, or by version.
+fn find_host_toolchain(name: &str) -> Result<PathBuf, Report> {
+    let installs = list_installs()?;
+    let (path, _) = installs
+        .iter()
+        .find(|(path, _)| path.file_name().is_some_and(|folder| folder == name))
+        .or_else(|| {
+            installs
+                .iter()
+                .find(|(_, metadata)| metadata.version == name)
+        })
+        .ok_or_else(|| InstallError::UnknownToolchain(name.into()))
+        .with_suggestion(|| "Use a version shown by `llvmgr list`")?;
+
+    let bin = path.join("bin");
+    for (tool, exe) in [("clang", "clang"), ("lld", "ld.lld")] {
+        if !bin.join(format!("{exe}{EXE_SUFFIX}")).exists() {
+            return Err(InstallError::IncompleteToolchain(name.into(), tool)).with_suggestion(
+                || format!("Install it with `llvmgr install llvm {name} --projects clang,lld`"),
+            );
+        }
+    }
+
+    Ok(bin)
+}
+
+/// How many linkers fit in memory at once, when the total memory is known.
+fn default_link_jobs(profile: Profile, jobs: usize) -> Option<usize> {
+    let memory = total_memory()?;
+    let link_jobs = (memory / profile.link_job_memory()) as usize;
+    Some(link_jobs.max(1).min(jobs.max(1)))
+}
+
+// parses lists like: "clang,lld", "clang;lld" or "none"
+fn parse_list(s: &str) -> Vec<String> {
+    if s.trim().eq_ignore_ascii_case("none") {
+        return vec![];
+    }
+
+    s.split([',', ';'])
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Replaces the `host` shorthand with the backend of this machine, so the recorded
+/// targets tell what the install supports.
+fn parse_targets(s: &str) -> Vec<String> {
+    parse_list(s)
+        .into_iter()
+        .map(|target| {
+            if target.eq_ignore_ascii_case("host") {
+                host_llvm_target().to_string()
+            } else {
+                target
+            }
+        })
+        .collect()
+}
+
+async fn resolve_version(version: &str) -> Result<Version, Report> {
+    let spec = version
+        .parse::<VersionSpec>()
+        .map_err(InstallError::InvalidVersion)
+        .with_suggestion(|| "Use `17`, `17.0`, `17.0.6`, `^18`, `>=17, <19` or `latest`")?;
+
+    let resolved = match spec.exact() {
+        Some(version) => version,
+        None => {
+            let index = release_index()
+                .await
+                .wrap_err("Unable to list LLVM releases")?;
+            spec.resolve(&index)
+                .ok_or_else(|| InstallError::NoMatchingRelease(version.into()))?
+        }
+    };
+    println!("Resolved llvm {version} to {resolved}");
+
+    Ok(resolved)
+}
+
+/// Fails on options that cannot be used together, or that miss another option.
+fn check_options(install: &InstallSubcommand) -> Result<(), InstallError> {
+    let conflicts = [
+        (
+            "--prebuilt",
+            install.prebuilt,
+            "--from-archive",
+            install.from_archive.is_some(),
+        ),
+        (
+            "--prebuilt",
+            install.prebuilt,
+            "--from-source",
+            install.from_source.is_some(),
+        ),
+        (
+            "--from-archive",
+            install.from_archive.is_some(),
+            "--from-source",
+            install.from_source.is_some(),
+        ),
+        (
+            "--git",
+            install.git.is_some(),
+            "<version>",
+            install.version.is_some(),
+        ),
+        (
+            "--git",
+            install.git.is_some(),
+            "--prebuilt",
+            install.prebuilt,
+        ),
+        (
+            "--git",
+            install.git.is_some(),
+            "--from-archive",
+            install.from_archive.is_some(),
+        ),
+        (
+            "--git",
+            install.git.is_some(),
+            "--from-source",
+            install.from_source.is_some(),
+        ),
+        (
+            "--bootstrap",
+            install.bootstrap,
+            "--host-toolchain",
+            install.host_toolchain.is_some(),
+        ),
+        (
+            "--git",
+            install.git.is_some(),
+            "--sha256",
+            install.sha256.is_some(),
+        ),
+        (
+            "--from-source",
+            install.from_source.is_some(),
+            "--sha256",
+            install.sha256.is_some(),
+        ),
+        (
+            "--git",
+            install.git.is_some(),
+            "--require-signature",
+            install.require_signature,
+        ),
+        (
+            "--from-source",
+            install.from_source.is_some(),
+            "--require-signature",
+            install.require_signature,
+        ),
+        (
+            "--rebuild",
+            install.rebuild,
+            "--cmake-arg",
+            !install.cmake_arg.is_empty(),
+        ),
+        (
+            "--rebuild",
+            install.rebuild,
+            "--projects",
+            install.projects.is_some(),
+        ),
+        (
+            "--rebuild",
+            install.rebuild,
+            "--runtimes",
+            install.runtimes.is_some(),
+        ),
+        (
+            "--rebuild",
+            install.rebuild,
+            "--targets",
+            install.targets.is_some(),
+        ),
+        (
+            "--rebuild",
+            install.rebuild,
+            "--experimental-targets",
+            install.experimental_targets.is_some(),
+        ),
+    ];
+    for (a, a_set, b, b_set) in conflicts {
+        if a_set && b_set {
+            return Err(InstallError::ConflictingOptions(a, b));
+        }
+    }
+
+    // Prebuilt binaries are not built, so these would only shape the build from source
+    // that happens when no binaries are found
+    let build_options = [
+        ("--cmake-arg", !install.cmake_arg.is_empty()),
+        ("--rebuild", install.rebuild),
+        ("--profile", install.profile != Profile::Release),
+        ("--assertions", install.assertions),
+        ("--projects", install.projects.is_some()),
+        ("--runtimes", install.runtimes.is_some()),
+        ("--targets", install.targets.is_some()),
+        (
+            "--experimental-targets",
+            install.experimental_targets.is_some(),
+        ),
+        ("--jobs", install.jobs.is_some()),
+        ("--link-jobs", install.link_jobs.is_some()),
+        (
+            "--compiler-cache",
+            install.compiler_cache != CompilerCache::Auto,
+        ),
+        ("--bootstrap", install.bootstrap),
+        ("--host-toolchain", install.host_toolchain.is_some()),
+        ("--clean", install.clean),
+    ];
+    if install.prebuilt {
+        if let Some((option, _)) = build_options.iter().find(|(_, set)| *set) {
+            return Err(InstallError::ConflictingOptions("--prebuilt", option));
+        }
+    }
+
+    let requirements = [(
+        "--rev",
+        install.rev.is_some(),
+        "--git",
+        install.git.is_some(),
+    )];
+    for (a, a_set, b, b_set) in requirements {
+        if a_set && !b_set {
+            return Err(InstallError::MissingOption(a, b));
+        }
+    }
+
+    let jobs = [
+        ("--jobs", install.jobs),
+        ("--link-jobs", install.link_jobs),
+        ("--download-jobs", install.download_jobs),
+    ];
+    for (option, jobs) in jobs {
+        if jobs == Some(0) {
+            return Err(InstallError::ZeroJobs(option));
+        }
+    }
+
+    Ok(())
+}
 
 pub(crate) async fn run(_: &Args, install: &InstallSubcommand) -> Result<(), Report> {
-    match (install.name.as_str(), install.version.as_str()) {
-        ("llvm", "16") => llvm_16().await,
-        ("llvm", "17") => llvm_17().await,
-        ("llvm", "18") => llvm_18().await,
-        _ => todo!(),
+    match install.name.as_str() {
+        "llvm" => {
+            check_options(install)?;
+
+            let (version, git) = match (install.git.as_ref(), install.version.as_ref()) {
+                (Some(repo), _) => {
+                    let rev = install.rev.as_deref().unwrap_or("HEAD");
+                    let revision = git_revision(repo, rev)?;
+                    println!(
+                        "Resolved llvm {rev} to {} ({})",
+                        revision.version, revision.short_hash
+                    );
+                    (revision.version, Some(revision))
+                }
+                (None, Some(version)) => (resolve_version(version).await?, None),
+                (None, None) => {
+                    return Err(InstallError::MissingVersion)
+                        .with_suggestion(|| "Pass a version such as `17`, or --git")
+                }
+            };
+
+            let config = Config::load().wrap_err("Unable to load the configuration")?;
+            let mirrors = Mirrors::new(&config.mirrors, config.mirror_template.as_deref());
+            let mut cmake_args = config.cmake_args;
+            cmake_args.extend(install.cmake_arg.iter().cloned());
+
+            let jobs = install.jobs.unwrap_or_else(|| {
+                std::thread::available_parallelism()
+                    .map(|n| n.get())
+                    .unwrap_or(1)
+            });
+            let link_jobs = install
+                .link_jobs
+                .or_else(|| default_link_jobs(install.profile, jobs));
+
+            if let Some(sha256) = install.sha256.as_ref() {
+                if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+                    return Err(InstallError::InvalidSha256(sha256.clone()))
+                        .with_suggestion(|| "Use the 64 hex digits printed by `sha256sum`");
+                }
+            }
+
+            let host_toolchain = install
+                .host_toolchain
+                .as_deref()
+                .map(find_host_toolchain)
+                .transpose()?;
+
+            let options = InstallOptions {
+                prebuilt: install.prebuilt,
+                from_archive: install.from_archive.clone(),
+                from_source: install.from_source.clone(),
+                git,
+                cmake_args,
+                rebuild: install.rebuild,
+                profile: install.profile,
+                assertions: install.assertions,
+                projects: install.projects.as_deref().map(parse_list),
+                runtimes: install.runtimes.as_deref().map(parse_list),
+                targets: install.targets.as_deref().map(parse_targets),
+                experimental_targets: install
+                    .experimental_targets
+                    .as_deref()
+                    .map(parse_targets)
+                    .unwrap_or_default(),
+                jobs,
+                link_jobs,
+                compiler_cache: install.compiler_cache,
+                bootstrap: install.bootstrap,
+                host_toolchain,
+                clean: install.clean,
+                sha256: install
+                    .sha256
+                    .as_ref()
+                    .map(|sha256| sha256.to_ascii_lowercase()),
+                require_signature: install.require_signature,
+                mirrors,
+                download_jobs: install.download_jobs.unwrap_or(4),
+            };
+            llvm(&version, &options).await
+        }
+        name => Err(InstallError::UnknownTool(name.into())).with_suggestion(|| "Options: llvm"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_options, InstallError};
+    use crate::InstallSubcommand;
+    use argp::FromArgs;
+
+    fn check(args: &[&str]) -> Result<(), InstallError> {
+        let install = InstallSubcommand::from_args(&["llvmgr", "install"], args).unwrap();
+        check_options(&install)
+    }
+
+    #[test]
+    fn rejects_build_options_with_prebuilt() {
+        assert!(check(&["llvm", "17", "--prebuilt"]).is_ok());
+        assert!(check(&["llvm", "17", "--prebuilt", "--download-jobs", "2"]).is_ok());
+        assert!(check(&["llvm", "17", "--prebuilt", "--compiler-cache", "auto"]).is_ok());
+
+        for option in [
+            &["--cmake-arg", "-DFOO=BAR"][..],
+            &["--rebuild"],
+            &["--profile", "debug"],
+            &["--assertions"],
+            &["--projects", "clang"],
+            &["--runtimes", "libcxx"],
+            &["--targets", "X86"],
+            &["--experimental-targets", "M68k"],
+            &["-j", "4"],
+            &["--link-jobs", "1"],
+            &["--compiler-cache", "off"],
+            &["--bootstrap"],
+            &["--host-toolchain", "17.0.6"],
+            &["--clean"],
+        ] {
+            let args = [&["llvm", "17", "--prebuilt"][..], option].concat();
+            assert!(
+                matches!(
+                    check(&args),
+                    Err(InstallError::ConflictingOptions("--prebuilt", _))
+                ),
+                "{option:?}"
+            );
+            let args = [&["llvm", "17"][..], option].concat();
+            assert!(check(&args).is_ok(), "{option:?}");
+        }
     }
 }
@@ -0,0 +1,39 @@
+use super::list_installs;
+use crate::{Args, ListSubcommand};
+use color_eyre::eyre::Report;
+
+fn join_or_none(items: &[String]) -> String {
+    if items.is_empty() {
+        "none".into()
+    } else {
+        items.join(", ")
+    }
+}
+
+pub(crate) fn run(_: &Args, _: &ListSubcommand) -> Result<(), Report> {
+    for (path, metadata) in list_installs()? {
+        let targets = match metadata.targets.as_ref() {
+            Some(targets) => targets.join(", "),
+            None => "all".into(),
+        };
+
+        println!("{}", path.display());
+        println!("  version: {} ({})", metadata.version, metadata.variant());
+        // Prebuilt binaries ship whatever the release managers built
+        if let Some(asset) = metadata.prebuilt.as_ref() {
+            println!("  prebuilt: {asset}");
+            continue;
+        }
+        println!("  projects: {}", join_or_none(&metadata.projects));
+        println!("  runtimes: {}", join_or_none(&metadata.runtimes));
+        println!("  targets: {targets}");
+        if !metadata.experimental_targets.is_empty() {
+            println!(
+                "  experimental targets: {}",
+                metadata.experimental_targets.join(", ")
+            );
+        }
+    }
+
+    Ok(())
+}
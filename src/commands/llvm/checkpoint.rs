@@ -0,0 +1,193 @@
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+const CHECKPOINT_FILE: &str = "llvmgr-checkpoint.json";
+
+#[derive(Error, Debug)]
+pub(crate) enum CheckpointError {
+    #[error("{0}")]
+    IO(std::io::Error),
+    #[error("{0}")]
+    Serialization(serde_json::Error),
+}
+
+/// A step of an install from source. Steps complete in the order they are listed,
+/// except the downloads and extractions of artifacts, which complete in any order.
+///
+/// Decompressing is part of [`Step::Extract`], as archives are extracted while they
+/// are decompressed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Step {
+    /// Archive fetched into the cache folder
+    Download(String),
+    /// Archive extracted into the version folder
+    Extract(String),
+    /// Build folder, and the arguments it was configured with
+    Configure {
+        build_dir: String,
+        args: Vec<String>,
+    },
+    /// Build folder
+    Build(String),
+    Install,
+    Register,
+}
+
+/// Steps completed by previous runs of an install, kept inside the version folder so
+/// an interrupted install resumes where it stopped.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Checkpoint {
+    #[serde(skip)]
+    path: PathBuf,
+    /// Where the sources come from. Installs from other sources start from scratch.
+    source: String,
+    steps: Vec<Step>,
+    /// How many steps were checked by this run
+    #[serde(skip)]
+    next: usize,
+}
+
+impl Checkpoint {
+    pub fn new(prefix: &Path, source: String) -> Checkpoint {
+        Checkpoint {
+            path: prefix.join(CHECKPOINT_FILE),
+            source,
+            steps: vec![],
+            next: 0,
+        }
+    }
+
+    /// The checkpoint of an interrupted install of the same sources, if any.
+    pub fn read(prefix: &Path, source: &str) -> Result<Option<Checkpoint>, CheckpointError> {
+        let path = prefix.join(CHECKPOINT_FILE);
+        let json = match std::fs::read_to_string(&path) {
+            Ok(json) => json,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(CheckpointError::IO(err)),
+        };
+
+        let mut checkpoint: Checkpoint =
+            serde_json::from_str(&json).map_err(CheckpointError::Serialization)?;
+        checkpoint.path = path;
+        Ok(Some(checkpoint).filter(|checkpoint| checkpoint.source == source))
+    }
+
+    /// Whether a previous run completed `step`.
+    ///
+    /// Steps must be checked in order. The first step that was not completed invalidates
+    /// every step recorded after it.
+    pub fn done(&mut self, step: &Step) -> bool {
+        if self.steps.get(self.next) == Some(step) {
+            self.next += 1;
+            true
+        } else {
+            self.steps.truncate(self.next);
+            false
+        }
+    }
+
+    /// Which of `steps`, which complete in any order, a previous run completed.
+    ///
+    /// Like [`Checkpoint::done`] for each step, except the steps recorded next only
+    /// have to be among `steps`, in whatever order.
+    pub fn done_unordered(&mut self, steps: &[Step]) -> Vec<bool> {
+        let recorded = self.steps[self.next..]
+            .iter()
+            .take_while(|step| steps.contains(step))
+            .count();
+        let done = steps
+            .iter()
+            .map(|step| self.steps[self.next..self.next + recorded].contains(step))
+            .collect::<Vec<_>>();
+
+        self.next += recorded;
+        if done.contains(&false) {
+            self.steps.truncate(self.next);
+        }
+        done
+    }
+
+    pub fn complete(&mut self, step: Step) -> Result<(), CheckpointError> {
+        self.steps.truncate(self.next);
+        self.steps.push(step);
+        self.next += 1;
+
+        if let Some(prefix) = self.path.parent() {
+            std::fs::create_dir_all(prefix).map_err(CheckpointError::IO)?;
+        }
+
+        // Written aside and renamed, so an interruption never leaves half a checkpoint
+        let json = serde_json::to_string_pretty(self).expect("this should not fail");
+        let tmp = self.path.with_extension("json.tmp");
+        std::fs::write(&tmp, json).map_err(CheckpointError::IO)?;
+        std::fs::rename(tmp, &self.path).map_err(CheckpointError::IO)
+    }
+
+    /// Forgets the steps once the install is complete, so installing again starts
+    /// from scratch.
+    pub fn remove(self) -> Result<(), CheckpointError> {
+        match std::fs::remove_file(&self.path) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(CheckpointError::IO(err)),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Checkpoint, Step};
+
+    fn download(name: &str) -> Step {
+        Step::Download(name.into())
+    }
+
+    fn extract(name: &str) -> Step {
+        Step::Extract(name.into())
+    }
+
+    #[test]
+    fn resumes_steps_completed_in_any_order() {
+        let dir = std::env::temp_dir().join(format!("llvmgr-{}-checkpoint", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let steps = [download("a"), extract("a"), download("b"), extract("b")];
+
+        // `a` failed to extract, after `b` was extracted
+        let mut checkpoint = Checkpoint::new(&dir, "source".into());
+        assert_eq!(checkpoint.done_unordered(&steps), [false; 4]);
+        for step in [download("b"), extract("b"), download("a")] {
+            checkpoint.complete(step).unwrap();
+        }
+
+        let mut checkpoint = Checkpoint::read(&dir, "source").unwrap().unwrap();
+        assert_eq!(checkpoint.done_unordered(&steps), [true, false, true, true]);
+        checkpoint.complete(extract("a")).unwrap();
+        checkpoint.complete(Step::Install).unwrap();
+
+        let mut checkpoint = Checkpoint::read(&dir, "source").unwrap().unwrap();
+        assert_eq!(checkpoint.done_unordered(&steps), [true; 4]);
+        assert!(checkpoint.done(&Step::Install));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn forgets_steps_after_missing_artifacts() {
+        let dir = std::env::temp_dir().join(format!("llvmgr-{}-forget", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut checkpoint = Checkpoint::new(&dir, "source".into());
+        for step in [download("a"), extract("a"), Step::Install] {
+            checkpoint.complete(step).unwrap();
+        }
+
+        // `b` is new
+        let mut checkpoint = Checkpoint::read(&dir, "source").unwrap().unwrap();
+        let steps = [download("a"), extract("a"), download("b"), extract("b")];
+        assert_eq!(
+            checkpoint.done_unordered(&steps),
+            [true, true, false, false]
+        );
+        assert!(!checkpoint.done(&Step::Install));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
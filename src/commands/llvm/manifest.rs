@@ -0,0 +1,458 @@
+use crate::commands::{config_path, host_triple, FileSystemError, Version, VersionSpec};
+use serde::Deserialize;
+use std::collections::HashMap;
+use thiserror::Error;
+
+const BUILTIN_MANIFEST: &str = include_str!("releases.toml");
+
+#[derive(Error, Debug)]
+pub(crate) enum ManifestError {
+    #[error("{0}")]
+    FileSystem(FileSystemError),
+    #[error("{0}")]
+    IO(std::io::Error),
+    #[error("{0}: {1}")]
+    Parse(String, toml::de::Error),
+    #[error("{0}")]
+    InvalidVersions(String),
+    #[error("no manifest entry for {0}")]
+    UnknownRelease(Version),
+    #[error("no {0} for {1}")]
+    MissingField(&'static str, Version),
+    #[error("project {0} is not available for {1}")]
+    UnsupportedProject(String, Version),
+    #[error("runtime {0} is not available for {1}")]
+    UnsupportedRuntime(String, Version),
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Defaults {
+    folder: Option<String>,
+    env_var: Option<String>,
+    cmake_args: Option<Vec<String>>,
+    prebuilt: Option<HashMap<String, Vec<String>>>,
+    projects: Option<Vec<String>>,
+    runtimes: Option<Vec<String>>,
+    default_projects: Option<Vec<String>>,
+    default_runtimes: Option<Vec<String>>,
+    signature: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ArtifactEntry {
+    url: String,
+    dest: String,
+    /// Only downloaded when this project or runtime is built
+    project: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ReleaseEntry {
+    versions: String,
+    folder: Option<String>,
+    env_var: Option<String>,
+    cmake_args: Option<Vec<String>>,
+    prebuilt: Option<HashMap<String, Vec<String>>>,
+    projects: Option<Vec<String>>,
+    runtimes: Option<Vec<String>>,
+    default_projects: Option<Vec<String>>,
+    default_runtimes: Option<Vec<String>>,
+    signature: Option<String>,
+    build_dir: String,
+    cmake_source_dir: String,
+    artifacts: Vec<ArtifactEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Manifest {
+    #[serde(default)]
+    defaults: Defaults,
+    #[serde(default, rename = "release")]
+    releases: Vec<ReleaseEntry>,
+    /// SHA-256 digests of downloads, by file name
+    #[serde(default)]
+    sha256: HashMap<String, String>,
+}
+
+/// A source archive and the folder, relative to the version folder, it is extracted into.
+pub(crate) struct Artifact {
+    pub file_name: String,
+    pub url: String,
+    pub dest: String,
+    pub project: Option<String>,
+    /// Url of the detached OpenPGP signature
+    pub signature: Option<String>,
+}
+
+/// Everything needed to build one LLVM version, as described by the manifest.
+pub(crate) struct Release {
+    pub version: Version,
+    /// Relative to the cache folder
+    pub folder: String,
+    pub env_var: String,
+    pub artifacts: Vec<Artifact>,
+    /// Relative to the version folder
+    pub build_dir: String,
+    /// Relative to the version folder
+    pub cmake_source_dir: String,
+    pub cmake_args: Vec<String>,
+    /// Patterns, possibly with `*`, of the release assets with prebuilt binaries for the host
+    pub prebuilt: Vec<String>,
+    /// Values accepted by LLVM_ENABLE_PROJECTS
+    pub projects: Vec<String>,
+    /// Values accepted by LLVM_ENABLE_RUNTIMES
+    pub runtimes: Vec<String>,
+    pub default_projects: Vec<String>,
+    pub default_runtimes: Vec<String>,
+    /// SHA-256 digests of downloads, by file name
+    pub sha256: HashMap<String, String>,
+}
+
+impl Release {
+    pub fn check_projects(
+        &self,
+        projects: &[String],
+        runtimes: &[String],
+    ) -> Result<(), ManifestError> {
+        if let Some(project) = projects.iter().find(|p| !self.projects.contains(p)) {
+            return Err(ManifestError::UnsupportedProject(
+                project.clone(),
+                self.version,
+            ));
+        }
+        if let Some(runtime) = runtimes.iter().find(|r| !self.runtimes.contains(r)) {
+            return Err(ManifestError::UnsupportedRuntime(
+                runtime.clone(),
+                self.version,
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn render(template: &str, version: &Version) -> String {
+    template
+        .replace("{version}", &version.to_string())
+        .replace("{major}", &version.major.to_string())
+        .replace("{minor}", &version.minor.to_string())
+        .replace("{patch}", &version.patch.to_string())
+}
+
+impl Manifest {
+    fn parse(name: &str, s: &str) -> Result<Manifest, ManifestError> {
+        let manifest: Manifest =
+            toml::from_str(s).map_err(|err| ManifestError::Parse(name.into(), err))?;
+
+        for release in manifest.releases.iter() {
+            release
+                .versions
+                .parse::<VersionSpec>()
+                .map_err(ManifestError::InvalidVersions)?;
+        }
+
+        Ok(manifest)
+    }
+
+    /// The built-in manifest, extended by `~/.config/llvmgr/releases.toml` when it exists.
+    pub fn load() -> Result<Manifest, ManifestError> {
+        let mut manifest = Manifest::parse("built-in manifest", BUILTIN_MANIFEST)?;
+
+        let user_path = config_path("releases.toml").map_err(ManifestError::FileSystem)?;
+        if user_path.exists() {
+            let s = std::fs::read_to_string(&user_path).map_err(ManifestError::IO)?;
+            let user = Manifest::parse(&user_path.display().to_string(), &s)?;
+            manifest.extend(user);
+        }
+
+        Ok(manifest)
+    }
+
+    /// Entries of `other` take precedence over entries of `self`.
+    fn extend(&mut self, other: Manifest) {
+        if other.defaults.folder.is_some() {
+            self.defaults.folder = other.defaults.folder;
+        }
+        if other.defaults.env_var.is_some() {
+            self.defaults.env_var = other.defaults.env_var;
+        }
+        if other.defaults.cmake_args.is_some() {
+            self.defaults.cmake_args = other.defaults.cmake_args;
+        }
+        if other.defaults.prebuilt.is_some() {
+            self.defaults.prebuilt = other.defaults.prebuilt;
+        }
+        if other.defaults.projects.is_some() {
+            self.defaults.projects = other.defaults.projects;
+        }
+        if other.defaults.runtimes.is_some() {
+            self.defaults.runtimes = other.defaults.runtimes;
+        }
+        if other.defaults.default_projects.is_some() {
+            self.defaults.default_projects = other.defaults.default_projects;
+        }
+        if other.defaults.default_runtimes.is_some() {
+            self.defaults.default_runtimes = other.defaults.default_runtimes;
+        }
+        if other.defaults.signature.is_some() {
+            self.defaults.signature = other.defaults.signature;
+        }
+
+        let builtin = std::mem::replace(&mut self.releases, other.releases);
+        self.releases.extend(builtin);
+
+        self.sha256.extend(other.sha256);
+    }
+
+    pub fn release(&self, version: &Version) -> Result<Release, ManifestError> {
+        let entry = self
+            .releases
+            .iter()
+            .find(|entry| {
+                entry
+                    .versions
+                    .parse::<VersionSpec>()
+                    .map(|spec| spec.matches(version))
+                    .unwrap_or(false)
+            })
+            .ok_or(ManifestError::UnknownRelease(*version))?;
+
+        let folder = entry
+            .folder
+            .as_ref()
+            .or(self.defaults.folder.as_ref())
+            .ok_or(ManifestError::MissingField("folder", *version))?;
+        let env_var = entry
+            .env_var
+            .as_ref()
+            .or(self.defaults.env_var.as_ref())
+            .ok_or(ManifestError::MissingField("env_var", *version))?;
+        let cmake_args = entry
+            .cmake_args
+            .as_ref()
+            .or(self.defaults.cmake_args.as_ref())
+            .cloned()
+            .unwrap_or_default();
+        let prebuilt = entry
+            .prebuilt
+            .as_ref()
+            .or(self.defaults.prebuilt.as_ref())
+            .and_then(|prebuilt| prebuilt.get(&host_triple()))
+            .cloned()
+            .unwrap_or_default();
+
+        let list = |entry: &Option<Vec<String>>, default: &Option<Vec<String>>| {
+            entry
+                .as_ref()
+                .or(default.as_ref())
+                .cloned()
+                .unwrap_or_default()
+        };
+        let projects = list(&entry.projects, &self.defaults.projects);
+        let runtimes = list(&entry.runtimes, &self.defaults.runtimes);
+        let default_projects = list(&entry.default_projects, &self.defaults.default_projects);
+        let default_runtimes = list(&entry.default_runtimes, &self.defaults.default_runtimes);
+
+        // An empty signature means the artifacts are not signed
+        let signature = entry
+            .signature
+            .as_ref()
+            .or(self.defaults.signature.as_ref())
+            .filter(|signature| !signature.is_empty());
+
+        let artifacts = entry
+            .artifacts
+            .iter()
+            .map(|artifact| {
+                let url = render(&artifact.url, version);
+                let file_name = url.rsplit('/').next().unwrap_or_default().to_string();
+                let signature =
+                    signature.map(|signature| render(signature, version).replace("{url}", &url));
+                Artifact {
+                    file_name,
+                    url,
+                    dest: render(&artifact.dest, version),
+                    project: artifact.project.clone(),
+                    signature,
+                }
+            })
+            .collect();
+
+        Ok(Release {
+            version: *version,
+            folder: render(folder, version),
+            env_var: render(env_var, version),
+            artifacts,
+            build_dir: render(&entry.build_dir, version),
+            cmake_source_dir: render(&entry.cmake_source_dir, version),
+            cmake_args: cmake_args.iter().map(|arg| render(arg, version)).collect(),
+            prebuilt: prebuilt.iter().map(|p| render(p, version)).collect(),
+            projects,
+            runtimes,
+            default_projects,
+            default_runtimes,
+            sha256: self.sha256.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Manifest, ManifestError, BUILTIN_MANIFEST};
+    use crate::commands::Version;
+
+    fn version(s: &str) -> Version {
+        s.parse().unwrap()
+    }
+
+    fn builtin() -> Manifest {
+        Manifest::parse("built-in manifest", BUILTIN_MANIFEST).unwrap()
+    }
+
+    fn file_names(manifest: &Manifest, v: &str) -> Vec<String> {
+        let release = manifest.release(&version(v)).unwrap();
+        release
+            .artifacts
+            .iter()
+            .map(|artifact| format!("{} -> {}", artifact.file_name, artifact.dest))
+            .collect()
+    }
+
+    #[test]
+    fn parses_builtin_manifest() {
+        let manifest = builtin();
+        for v in ["14.0.6", "15.0.7", "16.0.6", "17.0.6", "18.1.8", "19.1.7"] {
+            let release = manifest.release(&version(v)).unwrap();
+            assert_eq!(release.folder, v);
+            assert!(!release.artifacts.is_empty());
+            release
+                .check_projects(&release.default_projects, &release.default_runtimes)
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn resolves_release_layouts() {
+        let manifest = builtin();
+
+        let release = manifest.release(&version("15.0.7")).unwrap();
+        assert_eq!(release.build_dir, "llvm/build");
+        assert_eq!(release.cmake_source_dir, "llvm");
+        assert_eq!(
+            file_names(&manifest, "15.0.7"),
+            [
+                "llvm-15.0.7.src.tar.xz -> llvm",
+                "cmake-15.0.7.src.tar.xz -> cmake",
+                "clang-15.0.7.src.tar.xz -> clang",
+                "lld-15.0.7.src.tar.xz -> lld",
+                "clang-tools-extra-15.0.7.src.tar.xz -> clang-tools-extra",
+                "mlir-15.0.7.src.tar.xz -> mlir",
+                "polly-15.0.7.src.tar.xz -> polly",
+            ]
+        );
+
+        let release = manifest.release(&version("16.0.6")).unwrap();
+        assert_eq!(release.build_dir, "llvm/build");
+        assert_eq!(
+            file_names(&manifest, "16.0.6")[..3],
+            [
+                "llvm-16.0.6.src.tar.xz -> llvm",
+                "cmake-16.0.6.src.tar.xz -> cmake",
+                "third-party-16.0.6.src.tar.xz -> third-party",
+            ]
+        );
+        assert_eq!(release.artifacts[3].project.as_deref(), Some("clang"));
+
+        for v in ["17.0.6", "19.1.7"] {
+            let release = manifest.release(&version(v)).unwrap();
+            assert_eq!(release.build_dir, "src/build");
+            assert_eq!(release.cmake_source_dir, "src/llvm");
+            assert_eq!(
+                file_names(&manifest, v),
+                [format!("llvm-project-{v}.src.tar.xz -> src")]
+            );
+            assert_eq!(
+                release.artifacts[0].url,
+                format!("https://github.com/llvm/llvm-project/releases/download/llvmorg-{v}/llvm-project-{v}.src.tar.xz")
+            );
+            assert_eq!(
+                release.artifacts[0].signature,
+                Some(format!("{}.sig", release.artifacts[0].url))
+            );
+            assert_eq!(
+                release.env_var,
+                format!(
+                    "LLVM_SYS_{}_PREFIX",
+                    v.split('.').take(2).collect::<String>()
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn prefers_user_entries() {
+        let mut manifest = builtin();
+        let user = r#"
+            [defaults]
+            folder = "llvm-{version}"
+
+            [[release]]
+            versions = "17"
+            build_dir = "build"
+            cmake_source_dir = "llvm"
+            artifacts = [
+                { url = "https://mirror.example/llvm-{version}.tar.xz", dest = "." },
+            ]
+        "#;
+        manifest.extend(Manifest::parse("user", user).unwrap());
+
+        let release = manifest.release(&version("17.0.6")).unwrap();
+        assert_eq!(release.folder, "llvm-17.0.6");
+        assert_eq!(release.build_dir, "build");
+        assert_eq!(file_names(&manifest, "17.0.6"), ["llvm-17.0.6.tar.xz -> ."]);
+        // Built-in entries and defaults still apply to other releases
+        let release = manifest.release(&version("18.1.8")).unwrap();
+        assert_eq!(release.folder, "llvm-18.1.8");
+        assert_eq!(release.build_dir, "src/build");
+        assert_eq!(release.env_var, "LLVM_SYS_181_PREFIX");
+    }
+
+    #[test]
+    fn rejects_unknown_projects() {
+        let release = builtin().release(&version("16.0.6")).unwrap();
+        let names = |names: &[&str]| {
+            names
+                .iter()
+                .map(|name| name.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        release
+            .check_projects(&names(&["clang", "mlir"]), &[])
+            .unwrap();
+        assert!(matches!(
+            release.check_projects(&names(&["clang", "bolt"]), &[]),
+            Err(ManifestError::UnsupportedProject(project, _)) if project == "bolt"
+        ));
+        assert!(matches!(
+            release.check_projects(&[], &names(&["libcxx"])),
+            Err(ManifestError::UnsupportedRuntime(runtime, _)) if runtime == "libcxx"
+        ));
+    }
+
+    #[test]
+    fn uses_manifest_digests() {
+        let digest = "a".repeat(64);
+        let mut manifest = builtin();
+        let user = format!("[sha256]\n\"llvm-project-17.0.6.src.tar.xz\" = \"{digest}\"\n");
+        manifest.extend(Manifest::parse("user", &user).unwrap());
+
+        let release = manifest.release(&version("17.0.6")).unwrap();
+        let artifact = &release.artifacts[0];
+        assert_eq!(artifact.file_name, "llvm-project-17.0.6.src.tar.xz");
+        assert_eq!(release.sha256.get(&artifact.file_name), Some(&digest));
+    }
+}
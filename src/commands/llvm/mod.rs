@@ -1,277 +1,751 @@
+mod checkpoint;
+mod manifest;
+mod signature;
+
 use super::{
-    cache_path, dir_inside_cache_folder, download_ungz_untar, download_unxz_untar,
-    get_cmake_default_generator, move_dir, read_shell, remove_dir, search_cmake,
-    set_current_dir_inside_cache_folder, spawn_cmake, write_shell,
+    cache_path, cache_root, compiler_cache_stats, dir_inside_cache_folder, download_and_extract,
+    get_cmake_default_generator, git, host_triple, parse_cmake_set, quarantine,
+    read_install_metadata, read_shell, search_cmake, search_compiler_cache,
+    set_current_dir_inside_cache_folder, spawn_cmake, variant_suffix, write_install_metadata,
+    write_shell, Archive, CacheStats, CompilerCache, FileSystemError, InstallMetadata, Profile,
+    SpawnError, Version,
+};
+use crate::commands::{
+    http::{http_client, HttpClientError},
+    install::InstallOptions,
 };
-use crate::tasks::Tasks;
+use crate::tasks::{TaskRef, Tasks};
+use checkpoint::{Checkpoint, Step};
 use color_eyre::{
     eyre::WrapErr,
     eyre::{ContextCompat, Report},
     Help,
 };
+use futures_util::StreamExt;
+use manifest::{Artifact, Manifest, Release};
+use serde::Deserialize;
+use signature::{download_signature, Keyring, SignatureError};
+use std::{
+    collections::HashMap,
+    env::consts::EXE_SUFFIX,
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
 
-pub fn download_url(version: &str) -> (String, String) {
-    (
-        format!("https://github.com/llvm/llvm-project/archive/refs/tags/llvmorg-{version}.tar.gz"),
-        format!("llvmorg-{version}.tar.gz"),
-    )
+const RELEASE_TAGS_URL: &str =
+    "https://api.github.com/repos/llvm/llvm-project/git/matching-refs/tags/llvmorg-";
+const RELEASE_URL: &str = "https://api.github.com/repos/llvm/llvm-project/releases/tags";
+
+/// Projects of the toolchain built by the first stage of a bootstrap
+const STAGE1_PROJECTS: &[&str] = &["clang", "lld"];
+
+#[derive(Error, Debug)]
+pub(crate) enum ReleaseIndexError {
+    #[error("{0}")]
+    Client(HttpClientError),
+    #[error("http error")]
+    Reqwest(reqwest::Error),
+    #[error("http error {0}")]
+    Http(reqwest::StatusCode),
+    #[error("{0}")]
+    Serialization(serde_json::Error),
+    #[error("{0}")]
+    IO(std::io::Error),
+    #[error("{0}")]
+    FileSystem(FileSystemError),
 }
 
-pub async fn llvm_16() -> Result<(), Report> {
-    let cache_root_version = dir_inside_cache_folder("16.0.1")?;
-    let _ = std::fs::remove_dir_all(&cache_root_version);
+#[derive(Deserialize)]
+struct GitRef {
+    #[serde(rename = "ref")]
+    name: String,
+}
 
-    let mut tasks = Tasks::new();
+async fn fetch_release_index() -> Result<Vec<String>, ReleaseIndexError> {
+    let res = http_client()
+        .map_err(ReleaseIndexError::Client)?
+        .get(RELEASE_TAGS_URL)
+        .header(reqwest::header::ACCEPT, "application/vnd.github+json")
+        .send()
+        .await
+        .map_err(ReleaseIndexError::Reqwest)?;
+    let status = res.status();
+    if !status.is_success() {
+        return Err(ReleaseIndexError::Http(status));
+    }
 
-    let cmake = search_cmake()
-        .wrap_err("'cmake' cannot be found")
-        .with_suggestion(super::suggest_install_cmake)?;
-    let generator = get_cmake_default_generator(cmake)?;
+    let refs: Vec<GitRef> = res.json().await.map_err(ReleaseIndexError::Reqwest)?;
+    Ok(refs
+        .into_iter()
+        .filter_map(|r| r.name.strip_prefix("refs/tags/").map(str::to_string))
+        .collect())
+}
 
-    let t0 = tasks
-        .new_task("llvm-16.0.1.src.tar.xz")
-        .wrap_err("Cannot report progress")?;
-    let t1 = tasks
-        .new_task("cmake-16.0.1.src.tar.xz")
-        .wrap_err("Cannot report progress")?;
-    let t2 = tasks
-        .new_task("third-party-16.0.1.src.tar.xz")
-        .wrap_err("Cannot report progress")?;
-    let t3 = tasks
-        .new_task("Compilation")
-        .wrap_err("Cannot report progress")?;
-    let t4 = tasks
-        .new_task("Cleaning")
-        .wrap_err("Cannot report progress")?;
-    let t5 = tasks
-        .new_task("Env Vars")
-        .wrap_err("Cannot report progress")?;
+/// All final LLVM releases, excluding release candidates.
+///
+/// The list of tags is cached, so resolution still works when GitHub cannot be reached.
+pub(crate) async fn release_index() -> Result<Vec<Version>, ReleaseIndexError> {
+    let index_path = cache_root()
+        .map_err(ReleaseIndexError::FileSystem)?
+        .join("releases.json");
+
+    let tags = match fetch_release_index().await {
+        Ok(tags) => {
+            let json = serde_json::to_string_pretty(&tags).expect("this should not fail");
+            std::fs::write(&index_path, json).map_err(ReleaseIndexError::IO)?;
+            tags
+        }
+        Err(err) => match std::fs::read_to_string(&index_path) {
+            Ok(json) => serde_json::from_str(&json).map_err(ReleaseIndexError::Serialization)?,
+            Err(_) => return Err(err),
+        },
+    };
+
+    Ok(tags.iter().filter_map(|tag| tag.parse().ok()).collect())
+}
+
+#[derive(Deserialize)]
+struct GitHubRelease {
+    assets: Vec<ReleaseAsset>,
+}
 
-    // Download and uncompress files
-    let url = "https://github.com/llvm/llvm-project/releases/download/llvmorg-16.0.1/llvm-16.0.1.src.tar.xz";
-    download_unxz_untar(&t0, url, dir_inside_cache_folder("16.0.1/llvm")?)
+#[derive(Deserialize)]
+pub(crate) struct ReleaseAsset {
+    pub name: String,
+    #[serde(rename = "browser_download_url")]
+    pub url: String,
+}
+
+pub(crate) async fn release_assets(
+    version: &Version,
+) -> Result<Vec<ReleaseAsset>, ReleaseIndexError> {
+    let res = http_client()
+        .map_err(ReleaseIndexError::Client)?
+        .get(&format!("{RELEASE_URL}/llvmorg-{version}"))
+        .header(reqwest::header::ACCEPT, "application/vnd.github+json")
+        .send()
         .await
-        .wrap_err("Processing llvm-16.0.1.src.tar.xz")?;
-    t0.finish();
+        .map_err(ReleaseIndexError::Reqwest)?;
+    let status = res.status();
+    if status == reqwest::StatusCode::NOT_FOUND {
+        return Ok(vec![]);
+    }
+    if !status.is_success() {
+        return Err(ReleaseIndexError::Http(status));
+    }
 
-    let url = "https://github.com/llvm/llvm-project/releases/download/llvmorg-16.0.1/cmake-16.0.1.src.tar.xz";
-    download_unxz_untar(&t1, url, dir_inside_cache_folder("16.0.1/cmake")?).await?;
-    t1.finish();
+    let release: GitHubRelease = res.json().await.map_err(ReleaseIndexError::Reqwest)?;
+    Ok(release.assets)
+}
 
-    let url = "https://github.com/llvm/llvm-project/releases/download/llvmorg-16.0.1/third-party-16.0.1.src.tar.xz";
-    download_unxz_untar(&t2, url, dir_inside_cache_folder("16.0.1/third-party")?).await?;
-    t2.finish();
+// matches names against patterns like: "clang+llvm-17.0.6-x86_64-linux-gnu*.tar.xz"
+fn matches_pattern(pattern: &str, name: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == name,
+        Some((prefix, rest)) => {
+            let Some(name) = name.strip_prefix(prefix) else {
+                return false;
+            };
+            (0..=name.len())
+                .filter(|i| name.is_char_boundary(*i))
+                .any(|i| matches_pattern(rest, &name[i..]))
+        }
+    }
+}
 
-    // Delete downloaded files
-    t4.set_subtask("llvm-16.0.1.src.tar.xz");
-    let _ = std::fs::remove_file(cache_path("llvm-16.0.1.src.tar.xz")?);
+/// First asset matching the patterns, in pattern order.
+///
+/// Among assets matching the same pattern, the one built on the oldest distro is
+/// preferred because it requires the oldest glibc.
+fn find_prebuilt_asset(patterns: &[String], mut assets: Vec<ReleaseAsset>) -> Option<ReleaseAsset> {
+    assets.sort_by(|a, b| a.name.cmp(&b.name));
+    let i = patterns.iter().find_map(|pattern| {
+        assets
+            .iter()
+            .position(|asset| matches_pattern(pattern, &asset.name))
+    })?;
+    Some(assets.swap_remove(i))
+}
 
-    t4.set_subtask("cmake-16.0.1.src.tar.xz");
-    let _ = std::fs::remove_file(cache_path("cmake-16.0.1.src.tar.xz")?);
+/// Fails unless the extracted binaries have an executable `bin/clang`, so an archive
+/// extracted without its links or permission modes is not registered.
+fn check_prebuilt(root: &Path) -> Result<(), Report> {
+    let clang = root.join("bin").join(format!("clang{EXE_SUFFIX}"));
+    let metadata =
+        std::fs::metadata(&clang).wrap_err_with(|| format!("{} is missing", clang.display()))?;
 
-    t4.set_subtask("third-party-16.0.1.src.tar.xz");
-    let _ = std::fs::remove_file(cache_path("third-party-16.0.1.src.tar.xz")?);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if metadata.permissions().mode() & 0o111 == 0 {
+            return Err(color_eyre::eyre::eyre!(
+                "{} is not executable",
+                clang.display()
+            ));
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = metadata;
 
-    // Compile
-    set_current_dir_inside_cache_folder("16.0.1/llvm/build")?;
-    if generator.contains("Visual Studio") {
-        let cpus = if let Ok(cpus) = std::env::var("NUMBER_OF_PROCESSORS") {
-            cpus.parse::<usize>().unwrap_or(1)
-        } else {
-            1
-        };
+    Ok(())
+}
 
-        spawn_cmake(&t3, ["..", "-DLLVM_ENABLE_PROJECTS=lld;clang"])?;
-        spawn_cmake(
-            &t3,
-            [
-                "--build",
-                ".",
-                "--config",
-                "Release",
-                "-j",
-                &cpus.to_string(),
-            ],
-        )?;
+async fn llvm_prebuilt(
+    release: &Release,
+    asset: ReleaseAsset,
+    signature: Option<&str>,
+    keyring: &Keyring,
+    options: &InstallOptions,
+) -> Result<(), Report> {
+    let version_root_folder = dir_inside_cache_folder(&release.folder)?;
 
-        // Move outputs
-        t4.set_subtask("bin");
-        move_dir(
-            cache_path("16.0.1/llvm/build/Release/bin")?,
-            cache_path("16.0.1")?,
-        )?;
+    let mut tasks = Tasks::new();
+    let t_asset = tasks
+        .new_task(asset.name.as_str())
+        .wrap_err("Cannot report progress")?;
+    let t_shell = tasks
+        .new_task("Configuring shell")
+        .wrap_err("Cannot report progress")?;
 
-        t4.set_subtask("lib");
-        move_dir(
-            cache_path("16.0.1/llvm/build/Release/lib")?,
-            cache_path("16.0.1")?,
-        )?;
+    let _ = std::fs::remove_dir_all(&version_root_folder);
+
+    // Download, verify and uncompress binaries
+    let sha256 = options
+        .sha256
+        .as_deref()
+        .or_else(|| release.sha256.get(&asset.name).map(String::as_str));
+    let urls = options.mirrors.urls(&asset.url, &release.version);
+    let signature = signature.map(|url| options.mirrors.urls(url, &release.version));
+    download_and_extract(
+        &t_asset,
+        Archive::Download(&urls),
+        sha256,
+        async |path: &Path| {
+            let signature = signature.as_deref();
+            verify_download(
+                &t_asset,
+                keyring,
+                path,
+                signature,
+                options.require_signature,
+            )
+            .await
+        },
+        &version_root_folder,
+    )
+    .await?;
+    check_prebuilt(&version_root_folder)
+        .wrap_err_with(|| format!("Checking {}", asset.name))
+        .with_suggestion(|| "Build from source, without --prebuilt")?;
+    t_asset.finish();
+
+    // Setup env vars
+    t_shell.set_subtask("configuring shell");
+    let metadata = InstallMetadata {
+        version: release.version.to_string(),
+        prebuilt: Some(asset.name.clone()),
+        ..Default::default()
+    };
+    register_install(release, &metadata)?;
+    t_shell.finish();
+
+    Ok(())
+}
 
-        t4.set_subtask("include");
-        move_dir(cache_path("16.0.1/llvm/include")?, cache_path("16.0.1")?)?;
+/// Verifies an archive against its detached signature.
+///
+/// Without `require`, archives that are not signed, or are signed by unknown keys, are
+/// accepted with a warning.
+fn check_signature(
+    t: &TaskRef,
+    keyring: &Keyring,
+    path: &Path,
+    signature: Option<Vec<u8>>,
+    require: bool,
+) -> Result<(), SignatureError> {
+    let result = match signature {
+        _ if keyring.is_empty() => Err(SignatureError::NoKeys),
+        Some(signature) => keyring.verify(t, path, &signature),
+        None => Err(SignatureError::Unsigned(
+            path.file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string(),
+        )),
+    };
+    match result {
+        Err(
+            err @ (SignatureError::Unsigned(_)
+            | SignatureError::NoKeys
+            | SignatureError::UnknownKey(..)),
+        ) if !require => {
+            // Tasks are named after the archive already
+            let reason = match err {
+                SignatureError::UnknownKey(_, key) => format!("signed by an unknown key {key}"),
+                SignatureError::NoKeys => "no release keys to check the signature".into(),
+                _ => "not signed".into(),
+            };
+            t.warn(&format!("{reason}, so it was not verified"));
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/// Suggests where to save the release keys when they are missing.
+fn suggest_release_keys(result: Result<(), SignatureError>) -> Result<(), Report> {
+    match result {
+        Err(err @ (SignatureError::NoKeys | SignatureError::UnknownKey(..))) => Err(err)
+            .with_suggestion(|| {
+                "Save the keys of https://releases.llvm.org/release-keys.asc at ~/.config/llvmgr/release-keys.asc"
+            }),
+        result => Ok(result?),
+    }
+}
+
+/// Downloads, verifies and extracts an artifact of a release, skipping the download
+/// and the extraction when `done` by a previous run. Steps completed by this run are
+/// pushed to `completed`.
+async fn release_artifact(
+    t: &TaskRef,
+    release: &Release,
+    artifact: &Artifact,
+    done: (bool, bool),
+    keyring: &Keyring,
+    options: &InstallOptions,
+    completed: &mut Vec<Step>,
+) -> Result<(), Report> {
+    let (downloaded, extracted) = done;
+    if extracted {
+        t.finish_with_subtask("extracted by a previous run");
+        return Ok(());
+    }
+
+    let urls = options.mirrors.urls(&artifact.url, &release.version);
+    let archive = if downloaded {
+        Archive::Cached(cache_path(&artifact.file_name)?)
     } else {
-        spawn_cmake(
-            &t3,
-            [
-                "..",
-                "-DCMAKE_BUILD_TYPE=Release",
-                "-G",
-                "Ninja",
-                "-DLLVM_ENABLE_PROJECTS=lld;clang",
-            ],
-        )?;
-        spawn_cmake(&t3, ["--build", "."])?;
-        spawn_cmake(
-            &t3,
-            [
-                &format!("-DCMAKE_INSTALL_PREFIX={}", cache_root_version.display()),
-                "-P",
-                "cmake_install.cmake",
-            ],
-        )?;
+        Archive::Download(&urls)
+    };
+    let sha256 = options
+        .sha256
+        .as_deref()
+        .or_else(|| release.sha256.get(&artifact.file_name).map(String::as_str));
+    let signature = artifact
+        .signature
+        .as_ref()
+        .map(|url| options.mirrors.urls(url, &release.version));
+    let dest = cache_path(format!("{}/{}", release.folder, artifact.dest))?;
+    download_and_extract(
+        t,
+        archive,
+        sha256,
+        async |path: &Path| {
+            let signature = signature.as_deref();
+            verify_download(t, keyring, path, signature, options.require_signature).await?;
+            completed.push(Step::Download(artifact.file_name.clone()));
+            Ok(())
+        },
+        &dest,
+    )
+    .await?;
+    completed.push(Step::Extract(artifact.file_name.clone()));
+    t.finish();
 
-        // Move outputs
-        t4.set_subtask("bin");
-        move_dir(cache_path("16.0.1/llvm/build/bin")?, cache_path("16.0.1")?)?;
+    Ok(())
+}
 
-        t4.set_subtask("lib");
-        move_dir(cache_path("16.0.1/llvm/build/lib")?, cache_path("16.0.1")?)?;
+/// Verifies a download against the signature at `signature`, between downloading and
+/// extracting it. Downloads that do not match are moved to quarantine.
+async fn verify_download(
+    t: &TaskRef,
+    keyring: &Keyring,
+    path: &Path,
+    signature: Option<&[String]>,
+    require: bool,
+) -> Result<(), Report> {
+    // Without keys, a signature cannot be checked
+    let signature = match signature {
+        Some(urls) if !keyring.is_empty() => download_signature(urls, require)
+            .await
+            .wrap_err_with(|| format!("Downloading {}", urls.join(", ")))?,
+        _ => None,
+    };
 
-        t4.set_subtask("include");
-        move_dir(
-            cache_path("16.0.1/llvm/build/include")?,
-            cache_path("16.0.1")?,
-        )?;
+    match check_signature(t, keyring, path, signature, require) {
+        Err(err @ SignatureError::BadSignature(..)) => {
+            let quarantine = quarantine(path)?;
+            Err(err).wrap_err_with(|| format!("Moved to {}", quarantine.display()))
+        }
+        result => suggest_release_keys(result),
     }
+}
 
-    // Clean source code
-    t4.set_subtask("llvm");
-    remove_dir(cache_path("16.0.1/llvm")?)?;
-    t4.set_subtask("cmake");
-    remove_dir(cache_path("16.0.1/cmake")?)?;
-    t4.set_subtask("third-party");
-    remove_dir(cache_path("16.0.1/third-party")?)?;
-    t4.finish();
+fn register_install(release: &Release, metadata: &InstallMetadata) -> Result<(), Report> {
+    write_install_metadata(cache_path(&release.folder)?, metadata)?;
 
-    // Setup env vars
-    t5.set_subtask("configuring shell");
     let mut shell = read_shell()?;
-    let var = shell
-        .env_vars
-        .entry("LLVM_SYS_160_PREFIX".into())
-        .or_default();
-    *var = dir_inside_cache_folder("16.0.1")?.display().to_string();
+    let var = shell.env_vars.entry(release.env_var.clone()).or_default();
+    *var = cache_path(&release.folder)?.display().to_string();
     write_shell(&shell)?;
-    t5.finish();
-
     Ok(())
 }
 
-pub async fn llvm_17() -> Result<(), Report> {
-    let version = "17.0.6";
+/// A revision of a local llvm-project clone.
+pub(crate) struct GitRevision {
+    pub repo: PathBuf,
+    pub commit: String,
+    pub short_hash: String,
+    /// As declared by the build scripts of the revision
+    pub version: Version,
+}
 
-    let version_root_folder = dir_inside_cache_folder(version)?;
-    let llvm_source_code_folder = dir_inside_cache_folder(format!("{version}/src"))?;
+/// Version declared by `LLVMVersion.cmake` or, for older trees, `llvm/CMakeLists.txt`.
+fn cmake_version(contents: &str) -> Option<Version> {
+    let (mut major, mut minor, mut patch) = (None, None, None);
+    for line in contents.lines() {
+        if let Ok((_, (name, value))) = parse_cmake_set(line) {
+            match name {
+                "LLVM_VERSION_MAJOR" => major = value.parse().ok(),
+                "LLVM_VERSION_MINOR" => minor = value.parse().ok(),
+                "LLVM_VERSION_PATCH" => patch = value.parse().ok(),
+                _ => {}
+            }
+        }
+    }
 
-    let (source_code_url, source_code_filename) = download_url(version);
+    Some(Version {
+        major: major?,
+        minor: minor?,
+        patch: patch?,
+    })
+}
 
-    let mut tasks = Tasks::new();
+pub(crate) fn git_revision(repo: &Path, rev: &str) -> Result<GitRevision, Report> {
+    let repo = repo
+        .canonicalize()
+        .wrap_err_with(|| format!("Reading {}", repo.display()))?;
 
-    let cmake = search_cmake()
-        .wrap_err("'cmake' cannot be found")
-        .with_suggestion(super::suggest_install_cmake)?;
-    let generator = get_cmake_default_generator(cmake)?;
+    let commit = git(
+        &repo,
+        ["rev-parse", "--verify", &format!("{rev}^{{commit}}")],
+    )
+    .wrap_err_with(|| format!("Unable to find revision {rev}"))?;
+    let short_hash = git(&repo, ["rev-parse", "--short=12", &commit])?;
 
-    let t0 = tasks
-        .new_task(source_code_filename.as_str())
-        .wrap_err("Cannot report progress")?;
-    let t1 = tasks
-        .new_task("Compilation")
-        .wrap_err("Cannot report progress")?;
-    let t2 = tasks
-        .new_task("Installation")
-        .wrap_err("Cannot report progress")?;
-    let t3 = tasks
-        .new_task("Configuring shell")
-        .wrap_err("Cannot report progress")?;
+    let version = ["cmake/Modules/LLVMVersion.cmake", "llvm/CMakeLists.txt"]
+        .iter()
+        .find_map(|file| {
+            let contents = git(&repo, ["show", &format!("{commit}:{file}")]).ok()?;
+            cmake_version(&contents)
+        })
+        .wrap_err_with(|| format!("Unable to find the LLVM version of {short_hash}"))
+        .with_suggestion(|| "Is this a llvm-project repository?")?;
 
-    let _ = std::fs::remove_dir_all(&version_root_folder);
+    Ok(GitRevision {
+        repo,
+        commit,
+        short_hash,
+        version,
+    })
+}
 
-    // Download and uncompress source code
-    // 194990759 bytes
-    let llvm_tar_gz_file_path = download_ungz_untar(&t0, source_code_url, llvm_source_code_folder)
-        .await
-        .wrap_err("Downloading source code")?;
-    t0.set_subtask("Cleaning downloaded files...");
-    let _ = std::fs::remove_file(llvm_tar_gz_file_path);
-    t0.finish();
+/// Where the source code to compile comes from.
+enum Sources<'a> {
+    /// Artifacts downloaded as described by the manifest
+    Release(&'a [Artifact]),
+    /// A local source tarball
+    Archive(&'a Path),
+    /// A local source tree
+    Directory(PathBuf),
+    /// A revision of a local git clone
+    Git(&'a GitRevision),
+}
 
-    // Compilation
-    set_current_dir_inside_cache_folder("17.0.6/src/build")?;
-    if generator.contains("Visual Studio") {
-        let cpus = if let Ok(cpus) = std::env::var("NUMBER_OF_PROCESSORS") {
-            cpus.parse::<usize>().unwrap_or(1)
-        } else {
-            1
-        };
+impl Sources<'_> {
+    /// Identifies the sources in checkpoints.
+    fn checkpoint_source(&self) -> String {
+        match self {
+            Sources::Release(_) => "release".into(),
+            Sources::Archive(archive) => format!("archive {}", archive.display()),
+            Sources::Directory(dir) => format!("directory {}", dir.display()),
+            Sources::Git(revision) => format!("git {}", revision.commit),
+        }
+    }
+}
 
-        spawn_cmake(&t1, ["../llvm", "-DLLVM_ENABLE_PROJECTS=lld;clang"])?;
-        spawn_cmake(
-            &t1,
-            [
-                "--build",
-                ".",
-                "--config",
-                "Release",
-                "-j",
-                &cpus.to_string(),
-            ],
-        )?;
+/// Folder with the main LLVM `CMakeLists.txt` of a source tree, which may be the
+/// whole llvm-project or only its `llvm` folder.
+fn llvm_source_dir(root: &Path) -> PathBuf {
+    if root.join("llvm").join("CMakeLists.txt").exists() {
+        root.join("llvm")
     } else {
-        spawn_cmake(
-            &t1,
-            [
-                "../llvm",
-                "-DCMAKE_BUILD_TYPE=Release",
-                "-G",
-                "Ninja",
-                "-DLLVM_ENABLE_PROJECTS=lld;clang",
-            ],
-        )?;
-        spawn_cmake(&t1, ["--build", "."])?;
+        root.to_path_buf()
     }
+}
 
-    // Installation
-    spawn_cmake(
-        &t2,
-        [
-            &format!("-DCMAKE_INSTALL_PREFIX={}", version_root_folder.display()),
-            "-P",
-            "cmake_install.cmake",
-        ],
-    )?;
+/// A cmake project, configured and built inside the version folder.
+struct CMakeProject<'a> {
+    version_folder: &'a str,
+    source_dir: &'a Path,
+    visual_studio: bool,
+    profile: Profile,
+    jobs: usize,
+}
 
-    // Setup env vars
-    t3.set_subtask("configuring shell");
-    let mut shell = read_shell()?;
-    let var = shell
-        .env_vars
-        .entry("LLVM_SYS_170_PREFIX".into())
-        .or_default();
-    *var = dir_inside_cache_folder("17.0.6")?.display().to_string();
-    write_shell(&shell)?;
-    t3.finish();
+impl CMakeProject<'_> {
+    fn configure(&self, t: &TaskRef, args: &[String]) -> Result<(), SpawnError> {
+        let mut configure = vec![self.source_dir.display().to_string()];
+        if !self.visual_studio {
+            configure.extend([
+                format!("-DCMAKE_BUILD_TYPE={}", self.profile.cmake_build_type()),
+                "-G".into(),
+                "Ninja".into(),
+            ]);
+        }
+        configure.extend(args.iter().cloned());
+        spawn_cmake(t, configure)
+    }
+
+    fn build(&self, t: &TaskRef) -> Result<(), SpawnError> {
+        let jobs = self.jobs.to_string();
+        if self.visual_studio {
+            spawn_cmake(
+                t,
+                [
+                    "--build",
+                    ".",
+                    "--config",
+                    self.profile.cmake_build_type(),
+                    "-j",
+                    &jobs,
+                ],
+            )
+        } else {
+            spawn_cmake(t, ["--build", ".", "-j", &jobs])
+        }
+    }
+}
+
+/// Configures and builds the project at `build_dir`, skipping what a previous run
+/// completed. Builds are incremental, so an interrupted build continues where it stopped.
+fn build_stage(
+    t: &TaskRef,
+    checkpoint: &mut Checkpoint,
+    project: &CMakeProject,
+    build_dir: &str,
+    args: Vec<String>,
+    compiler_cache: Option<&(CompilerCache, PathBuf)>,
+) -> Result<(), Report> {
+    set_current_dir_inside_cache_folder(format!("{}/{build_dir}", project.version_folder))?;
+
+    let configure = Step::Configure {
+        build_dir: build_dir.into(),
+        args: args.clone(),
+    };
+    if !checkpoint.done(&configure) {
+        project.configure(t, &args)?;
+        checkpoint.complete(configure)?;
+    }
+
+    let build = Step::Build(build_dir.into());
+    if checkpoint.done(&build) {
+        t.finish_with_subtask("built by a previous run");
+        return Ok(());
+    }
+    let stats_before = compiler_cache.and_then(|(_, exe)| compiler_cache_stats(exe));
+    project.build(t)?;
+    checkpoint.complete(build)?;
+    finish_compilation(t, compiler_cache, stats_before);
 
     Ok(())
 }
 
-pub async fn llvm_18() -> Result<(), Report> {
-    let version = "18.1.2";
+/// Finishes a compilation task with the cache hits and misses since `before`, when known.
+fn finish_compilation(
+    t: &TaskRef,
+    compiler_cache: Option<&(CompilerCache, PathBuf)>,
+    before: Option<CacheStats>,
+) {
+    let after = compiler_cache.and_then(|(_, exe)| compiler_cache_stats(exe));
+    match (compiler_cache, before, after) {
+        (Some((cache, _)), Some(before), Some(after)) => t.finish_with_subtask(&format!(
+            "{cache}: {} hits, {} misses",
+            after.hits.saturating_sub(before.hits),
+            after.misses.saturating_sub(before.misses)
+        )),
+        _ => t.finish(),
+    }
+}
+
+pub async fn llvm(version: &Version, options: &InstallOptions) -> Result<(), Report> {
+    let release = Manifest::load()
+        .wrap_err("Unable to load the release manifest")?
+        .release(version)
+        .with_suggestion(|| "Describe this release at ~/.config/llvmgr/releases.toml")?;
 
-    let version_root_folder = dir_inside_cache_folder(version)?;
-    let llvm_source_code_folder = dir_inside_cache_folder(format!("{version}/src"))?;
+    let keyring = Keyring::load().wrap_err("Unable to load the release keys")?;
 
-    let (source_code_url, source_code_filename) = download_url(version);
+    if options.prebuilt {
+        let assets = release_assets(version)
+            .await
+            .wrap_err("Unable to list release assets")?;
+        let signatures = assets
+            .iter()
+            .filter(|asset| asset.name.ends_with(".sig"))
+            .map(|asset| (asset.name.clone(), asset.url.clone()))
+            .collect::<HashMap<_, _>>();
+        match find_prebuilt_asset(&release.prebuilt, assets) {
+            Some(asset) => {
+                let signature = signatures.get(&format!("{}.sig", asset.name)).cloned();
+                return llvm_prebuilt(&release, asset, signature.as_deref(), &keyring, options)
+                    .await;
+            }
+            // The digest was meant for the prebuilt binaries, not the sources
+            None if options.sha256.is_some() => {
+                return Err(color_eyre::eyre::eyre!(
+                    "No prebuilt binaries of {version} for {} to check --sha256 against",
+                    host_triple()
+                ))
+                .with_suggestion(|| "Build from source, without --prebuilt and --sha256");
+            }
+            None => println!(
+                "No prebuilt binaries of {version} for {}, building from source",
+                host_triple()
+            ),
+        }
+    }
+    let mut release = release;
+    if let Some(revision) = options.git.as_ref() {
+        release.folder = format!("{}-git-{}", release.folder, revision.short_hash);
+    }
+    release.folder += &variant_suffix(options.profile, options.assertions);
+    let version_folder = release.folder.as_str();
+
+    let version_root_folder = dir_inside_cache_folder(version_folder)?;
+
+    // Options of the previous install must be read before it is removed
+    let previous = if options.rebuild {
+        let previous = read_install_metadata(&version_root_folder)?
+            .wrap_err_with(|| format!("{version_folder} was not installed before"))?;
+        if let Some(asset) = previous.prebuilt.as_ref() {
+            return Err(color_eyre::eyre::eyre!(
+                "{version_folder} was installed from {asset}, not built"
+            ))
+            .with_suggestion(|| "Install it again with --prebuilt");
+        }
+        Some(previous)
+    } else {
+        None
+    };
+    let (cmake_args, projects, runtimes, targets, experimental_targets) = match previous {
+        Some(previous) => (
+            previous.cmake_args,
+            previous.projects,
+            previous.runtimes,
+            previous.targets,
+            previous.experimental_targets,
+        ),
+        None => (
+            options.cmake_args.clone(),
+            options
+                .projects
+                .clone()
+                .unwrap_or_else(|| release.default_projects.clone()),
+            options
+                .runtimes
+                .clone()
+                .unwrap_or_else(|| release.default_runtimes.clone()),
+            options.targets.clone(),
+            options.experimental_targets.clone(),
+        ),
+    };
+    release
+        .check_projects(&projects, &runtimes)
+        .with_suggestion(|| {
+            format!(
+                "Projects: {}. Runtimes: {}",
+                release.projects.join(", "),
+                release.runtimes.join(", ")
+            )
+        })?;
+    // Stage 1 is built from the clang and lld sources, whatever stage 2 builds
+    release
+        .artifacts
+        .retain(|artifact| match &artifact.project {
+            Some(project) => {
+                projects.contains(project)
+                    || runtimes.contains(project)
+                    || (options.bootstrap && STAGE1_PROJECTS.contains(&project.as_str()))
+            }
+            None => true,
+        });
+
+    let sources = match (&options.from_archive, &options.from_source) {
+        _ if options.git.is_some() => Sources::Git(options.git.as_ref().expect("checked above")),
+        (Some(archive), _) => Sources::Archive(archive),
+        (None, Some(dir)) => {
+            let dir = dir
+                .canonicalize()
+                .wrap_err_with(|| format!("Reading {}", dir.display()))?;
+            if dir.starts_with(&version_root_folder) {
+                return Err(color_eyre::eyre::eyre!(
+                    "{} is inside {}, which is cleaned before building",
+                    dir.display(),
+                    version_root_folder.display()
+                ));
+            }
+            Sources::Directory(dir)
+        }
+        (None, None) => {
+            if options.sha256.is_some() && release.artifacts.len() > 1 {
+                return Err(color_eyre::eyre::eyre!(
+                    "--sha256 needs a single download, but {version} has {}",
+                    release.artifacts.len()
+                ))
+                .with_suggestion(|| "List the digests at ~/.config/llvmgr/releases.toml");
+            }
+            Sources::Release(&release.artifacts)
+        }
+    };
+
+    if let Some(bin) = options.host_toolchain.as_ref() {
+        if bin.starts_with(&version_root_folder) {
+            return Err(color_eyre::eyre::eyre!(
+                "The host toolchain is {}, which is cleaned before building",
+                version_root_folder.display()
+            ))
+            .with_suggestion(|| "Use another installed version as the host toolchain");
+        }
+    }
+
+    let compiler_cache = match options.compiler_cache {
+        CompilerCache::Off => None,
+        CompilerCache::Auto => search_compiler_cache(CompilerCache::Auto),
+        cache => Some(
+            search_compiler_cache(cache)
+                .wrap_err_with(|| format!("'{cache}' cannot be found"))
+                .with_suggestion(|| format!("Install {cache}, or use --compiler-cache off"))?,
+        ),
+    };
+
+    let checkpoint = if options.clean {
+        None
+    } else {
+        Checkpoint::read(&version_root_folder, &sources.checkpoint_source())
+            .wrap_err("Unable to read the checkpoint of the previous install")
+            .with_suggestion(|| "Use --clean to start from scratch")?
+    };
+    let mut checkpoint = match checkpoint {
+        Some(checkpoint) => {
+            println!("Resuming the install of {version_folder}");
+            checkpoint
+        }
+        None => {
+            let _ = std::fs::remove_dir_all(&version_root_folder);
+            Checkpoint::new(&version_root_folder, sources.checkpoint_source())
+        }
+    };
 
     let mut tasks = Tasks::new();
 
@@ -280,85 +754,378 @@ pub async fn llvm_18() -> Result<(), Report> {
         .with_suggestion(super::suggest_install_cmake)?;
     let generator = get_cmake_default_generator(cmake)?;
 
-    let t0 = tasks
-        .new_task(source_code_filename.as_str())
-        .wrap_err("Cannot report progress")?;
-    let t1 = tasks
-        .new_task("Compilation")
+    let mut source_tasks = vec![];
+    match &sources {
+        Sources::Release(artifacts) => {
+            for artifact in artifacts.iter() {
+                let t = tasks
+                    .new_task(artifact.file_name.as_str())
+                    .wrap_err("Cannot report progress")?;
+                source_tasks.push(t);
+            }
+        }
+        Sources::Archive(archive) => {
+            let name = archive
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default();
+            let t = tasks
+                .new_task(name.as_str())
+                .wrap_err("Cannot report progress")?;
+            source_tasks.push(t);
+        }
+        Sources::Git(revision) => {
+            let t = tasks
+                .new_task(&format!("git archive {}", revision.short_hash))
+                .wrap_err("Cannot report progress")?;
+            source_tasks.push(t);
+        }
+        Sources::Directory(_) => {}
+    }
+    let t_stage1 = if options.bootstrap || options.host_toolchain.is_some() {
+        let t = tasks
+            .new_task("Compilation (stage 1)")
+            .wrap_err("Cannot report progress")?;
+        Some(t)
+    } else {
+        None
+    };
+    let t_compilation = tasks
+        .new_task(if t_stage1.is_some() {
+            "Compilation (stage 2)"
+        } else {
+            "Compilation"
+        })
         .wrap_err("Cannot report progress")?;
-    let t2 = tasks
+    let t_installation = tasks
         .new_task("Installation")
         .wrap_err("Cannot report progress")?;
-    let t3 = tasks
+    let t_shell = tasks
         .new_task("Configuring shell")
         .wrap_err("Cannot report progress")?;
 
-    let _ = std::fs::remove_dir_all(&version_root_folder);
+    let (cmake_source_dir, build_dir) = match &sources {
+        Sources::Release(artifacts) => {
+            // Steps are checked before artifacts are processed concurrently
+            let steps = artifacts
+                .iter()
+                .flat_map(|artifact| {
+                    [
+                        Step::Download(artifact.file_name.clone()),
+                        Step::Extract(artifact.file_name.clone()),
+                    ]
+                })
+                .collect::<Vec<_>>();
+            let done = checkpoint.done_unordered(&steps);
+            let pending = artifacts
+                .iter()
+                .zip(source_tasks.iter())
+                .zip(done.chunks(2))
+                .map(|((artifact, t), done)| (artifact, t, done[0], done[1]))
+                .collect::<Vec<_>>();
 
-    // Download and uncompress source code
-    // 205541214 bytes
-    let llvm_tar_gz_file_path = download_ungz_untar(&t0, source_code_url, llvm_source_code_folder)
-        .await
-        .wrap_err("Downloading source code")?;
-    t0.set_subtask("Cleaning downloaded files...");
-    let _ = std::fs::remove_file(llvm_tar_gz_file_path);
-    t0.finish();
+            let release = &release;
+            let keyring = &keyring;
+            let mut results = futures_util::stream::iter(pending.iter().enumerate().map(
+                |(i, (artifact, t, downloaded, extracted))| async move {
+                    let mut completed = vec![];
+                    let result = release_artifact(
+                        t,
+                        release,
+                        artifact,
+                        (*downloaded, *extracted),
+                        keyring,
+                        options,
+                        &mut completed,
+                    )
+                    .await;
+                    (i, completed, result)
+                },
+            ))
+            .buffer_unordered(options.download_jobs);
+
+            // Steps are saved as soon as their artifact is done, so an interruption
+            // does not lose them, and are kept even when another artifact failed
+            let mut error = None;
+            while let Some((i, completed, result)) = results.next().await {
+                for step in completed {
+                    checkpoint.complete(step)?;
+                }
+                if let Err(err) = result {
+                    if error.as_ref().is_none_or(|(first, _)| i < *first) {
+                        error = Some((i, err));
+                    }
+                }
+            }
+            if let Some((_, err)) = error {
+                return Err(err);
+            }
+
+            (
+                version_root_folder.join(&release.cmake_source_dir),
+                release.build_dir.clone(),
+            )
+        }
+        Sources::Archive(archive) => {
+            // Uncompress source code
+            let t = &source_tasks[0];
+            let dest = format!("{version_folder}/src");
+            let extract = Step::Extract(archive.display().to_string());
+            if checkpoint.done(&extract) {
+                t.finish_with_subtask("extracted by a previous run");
+            } else {
+                let file_name = archive.file_name().unwrap_or_default().to_string_lossy();
+                let sha256 = options
+                    .sha256
+                    .as_deref()
+                    .or_else(|| release.sha256.get(file_name.as_ref()).map(String::as_str));
+                download_and_extract(
+                    t,
+                    Archive::Local(archive),
+                    sha256,
+                    async |archive: &Path| {
+                        // A signature is looked for next to the archive
+                        let mut signature_path = archive.as_os_str().to_owned();
+                        signature_path.push(".sig");
+                        let signature = std::fs::read(signature_path).ok();
+                        suggest_release_keys(check_signature(
+                            t,
+                            &keyring,
+                            archive,
+                            signature,
+                            options.require_signature,
+                        ))
+                        .wrap_err_with(|| format!("Verifying {}", archive.display()))
+                    },
+                    &cache_path(&dest)?,
+                )
+                .await?;
+                checkpoint.complete(extract)?;
+                t.finish();
+            }
+
+            (llvm_source_dir(&cache_path(&dest)?), "build".into())
+        }
+        Sources::Git(revision) => {
+            // Export and uncompress source code
+            let t = &source_tasks[0];
+            let file_name = format!("llvm-project-{}.tar.gz", revision.short_hash);
+            let archive = cache_path(&file_name)?;
+            let download = Step::Download(file_name.clone());
+            if !checkpoint.done(&download) {
+                t.set_subtask("exporting");
+                git(
+                    &revision.repo,
+                    [
+                        "archive",
+                        "--format=tar.gz",
+                        "--prefix=llvm-project/",
+                        "-o",
+                        &archive.display().to_string(),
+                        &revision.commit,
+                    ],
+                )
+                .wrap_err_with(|| format!("Exporting {}", revision.short_hash))?;
+                checkpoint.complete(download)?;
+            }
+
+            let dest = format!("{version_folder}/src");
+            let extract = Step::Extract(file_name);
+            if checkpoint.done(&extract) {
+                t.finish_with_subtask("extracted by a previous run");
+            } else {
+                // Exported from a local clone, there is nothing to verify
+                download_and_extract(
+                    t,
+                    Archive::Cached(archive),
+                    None,
+                    async |_: &Path| Ok(()),
+                    &cache_path(&dest)?,
+                )
+                .await?;
+                checkpoint.complete(extract)?;
+                t.finish();
+            }
+
+            (llvm_source_dir(&cache_path(&dest)?), "build".into())
+        }
+        Sources::Directory(dir) => (llvm_source_dir(dir), "build".into()),
+    };
+
+    // Arguments shared by every stage
+    let mut common_args = vec![format!("-DLLVM_PARALLEL_COMPILE_JOBS={}", options.jobs)];
+    if let Some(link_jobs) = options.link_jobs {
+        common_args.push(format!("-DLLVM_PARALLEL_LINK_JOBS={link_jobs}"));
+    }
+    if let Some((_, exe)) = compiler_cache.as_ref() {
+        common_args.push(format!("-DCMAKE_C_COMPILER_LAUNCHER={}", exe.display()));
+        common_args.push(format!("-DCMAKE_CXX_COMPILER_LAUNCHER={}", exe.display()));
+    }
+    let visual_studio = generator.contains("Visual Studio");
+
+    // Stage 1: clang and lld, built by the host compiler
+    let toolchain = match (&t_stage1, &options.host_toolchain) {
+        (Some(t_stage1), Some(bin)) => {
+            t_stage1.finish_with_subtask(&format!("using {}", bin.display()));
+            Some(bin.clone())
+        }
+        (Some(t_stage1), None) => {
+            let mut args = vec![
+                "-DLLVM_ENABLE_ASSERTIONS=OFF".into(),
+                format!("-DLLVM_ENABLE_PROJECTS={}", STAGE1_PROJECTS.join(";")),
+                "-DLLVM_TARGETS_TO_BUILD=host".into(),
+            ];
+            args.extend(common_args.iter().cloned());
+            args.extend(release.cmake_args.iter().cloned());
+
+            let project = CMakeProject {
+                version_folder,
+                source_dir: &cmake_source_dir,
+                visual_studio,
+                profile: Profile::Release,
+                jobs: options.jobs,
+            };
+            build_stage(
+                t_stage1,
+                &mut checkpoint,
+                &project,
+                "stage1",
+                args,
+                compiler_cache.as_ref(),
+            )?;
+
+            let stage1 = cache_path(format!("{version_folder}/stage1"))?;
+            if visual_studio {
+                Some(stage1.join("Release").join("bin"))
+            } else {
+                Some(stage1.join("bin"))
+            }
+        }
+        (None, _) => None,
+    };
 
     // Compilation
-    set_current_dir_inside_cache_folder(format!("{version}/src/build"))?;
-    if generator.contains("Visual Studio") {
-        let cpus = if let Ok(cpus) = std::env::var("NUMBER_OF_PROCESSORS") {
-            cpus.parse::<usize>().unwrap_or(1)
-        } else {
-            1
-        };
+    let mut configure_args = vec![
+        format!(
+            "-DLLVM_ENABLE_ASSERTIONS={}",
+            if options.assertions { "ON" } else { "OFF" }
+        ),
+        format!("-DLLVM_ENABLE_PROJECTS={}", projects.join(";")),
+        format!("-DLLVM_ENABLE_RUNTIMES={}", runtimes.join(";")),
+        format!(
+            "-DLLVM_TARGETS_TO_BUILD={}",
+            targets
+                .as_ref()
+                .map(|targets| targets.join(";"))
+                .unwrap_or_else(|| "all".into())
+        ),
+        format!(
+            "-DLLVM_EXPERIMENTAL_TARGETS_TO_BUILD={}",
+            experimental_targets.join(";")
+        ),
+    ];
+    configure_args.extend(common_args);
+    if let Some(bin) = toolchain.as_ref() {
+        configure_args.extend([
+            format!(
+                "-DCMAKE_C_COMPILER={}",
+                bin.join(format!("clang{EXE_SUFFIX}")).display()
+            ),
+            format!(
+                "-DCMAKE_CXX_COMPILER={}",
+                bin.join(format!("clang++{EXE_SUFFIX}")).display()
+            ),
+            "-DLLVM_USE_LINKER=lld".into(),
+        ]);
+    }
+    configure_args.extend(release.cmake_args.iter().cloned());
+    configure_args.extend(cmake_args.iter().cloned());
 
-        spawn_cmake(&t1, ["../llvm", "-DLLVM_ENABLE_PROJECTS=lld;clang"])?;
-        spawn_cmake(
-            &t1,
-            [
-                "--build",
-                ".",
-                "--config",
-                "Release",
-                "-j",
-                &cpus.to_string(),
-            ],
-        )?;
+    // Visual Studio ignores CMAKE_<LANG>_COMPILER, so clang builds always use Ninja
+    let project = CMakeProject {
+        version_folder,
+        source_dir: &cmake_source_dir,
+        visual_studio: visual_studio && toolchain.is_none(),
+        profile: options.profile,
+        jobs: options.jobs,
+    };
+    build_stage(
+        &t_compilation,
+        &mut checkpoint,
+        &project,
+        &build_dir,
+        configure_args,
+        compiler_cache.as_ref(),
+    )?;
+
+    // Installation
+    if checkpoint.done(&Step::Install) {
+        t_installation.finish_with_subtask("installed by a previous run");
     } else {
         spawn_cmake(
-            &t1,
+            &t_installation,
             [
-                "../llvm",
-                "-DCMAKE_BUILD_TYPE=Release",
-                "-G",
-                "Ninja",
-                "-DLLVM_ENABLE_PROJECTS=lld;clang",
+                &format!("-DCMAKE_INSTALL_PREFIX={}", version_root_folder.display()),
+                // Multi-config generators install nothing without a configuration
+                &format!(
+                    "-DCMAKE_INSTALL_CONFIG_NAME={}",
+                    options.profile.cmake_build_type()
+                ),
+                "-P",
+                "cmake_install.cmake",
             ],
         )?;
-        spawn_cmake(&t1, ["--build", "."])?;
+        checkpoint.complete(Step::Install)?;
+        t_installation.finish();
     }
 
-    // Installation
-    spawn_cmake(
-        &t2,
-        [
-            &format!("-DCMAKE_INSTALL_PREFIX={}", version_root_folder.display()),
-            "-P",
-            "cmake_install.cmake",
-        ],
-    )?;
-
     // Setup env vars
-    t3.set_subtask("configuring shell");
-    let mut shell = read_shell()?;
-    let var = shell
-        .env_vars
-        .entry("LLVM_SYS_180_PREFIX".into())
-        .or_default();
-    *var = dir_inside_cache_folder(version)?.display().to_string();
-    write_shell(&shell)?;
-    t3.finish();
+    t_shell.set_subtask("configuring shell");
+    let metadata = InstallMetadata {
+        version: release.version.to_string(),
+        profile: options.profile,
+        assertions: options.assertions,
+        cmake_args,
+        projects,
+        runtimes,
+        targets,
+        experimental_targets,
+        prebuilt: None,
+    };
+    if !checkpoint.done(&Step::Register) {
+        register_install(&release, &metadata)?;
+        checkpoint.complete(Step::Register)?;
+    }
+    checkpoint.remove()?;
+    t_shell.finish();
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{verify_download, Keyring};
+    use crate::{
+        commands::test_server::{Response, TestServer},
+        tasks::Tasks,
+    };
+    use std::path::Path;
+
+    #[tokio::test]
+    async fn skips_signatures_without_keys() {
+        let server = TestServer::start(|_| Response::new(200).body(b"signature")).await;
+        let urls = [format!("{}/a.tar.xz.sig", server.url)];
+        let keyring = Keyring::parse("empty", "").unwrap();
+        let mut tasks = Tasks::hidden();
+        let t = tasks.new_task("test").unwrap();
+
+        let path = Path::new("a.tar.xz");
+        verify_download(&t, &keyring, path, Some(&urls), false)
+            .await
+            .unwrap();
+        assert!(verify_download(&t, &keyring, path, Some(&urls), true)
+            .await
+            .is_err());
+        assert!(server.requests().is_empty());
+    }
+}
@@ -0,0 +1,316 @@
+use crate::commands::{
+    config_path,
+    http::{http_client, HttpClientError},
+    FileSystemError, ProgressReader,
+};
+use crate::tasks::TaskRef;
+use pgp::{types::PublicKeyTrait, Deserializable, SignedPublicKey, StandaloneSignature};
+use std::path::Path;
+use thiserror::Error;
+
+/// Keys of the LLVM release managers, from https://releases.llvm.org/release-keys.asc
+const BUNDLED_KEYS: &str = include_str!("release-keys.asc");
+
+const KEY_BLOCK: &str = "-----BEGIN PGP PUBLIC KEY BLOCK-----";
+
+#[derive(Error, Debug)]
+pub(crate) enum SignatureError {
+    #[error("{0}")]
+    FileSystem(FileSystemError),
+    #[error("{0}")]
+    IO(std::io::Error),
+    #[error("{0}")]
+    Client(HttpClientError),
+    #[error("http error")]
+    Reqwest(reqwest::Error),
+    #[error("http error {0}")]
+    Http(reqwest::StatusCode),
+    #[error("{0}: {1}")]
+    Keys(String, pgp::errors::Error),
+    #[error("invalid signature: {0}")]
+    Parse(pgp::errors::Error),
+    #[error("no release keys")]
+    NoKeys,
+    #[error("{0} is not signed")]
+    Unsigned(String),
+    #[error("{0} is signed by an unknown key {1}")]
+    UnknownKey(String, String),
+    #[error("{0} does not match its signature: {1}")]
+    BadSignature(String, pgp::errors::Error),
+}
+
+/// Downloads a detached signature from the first of `urls` that has it, or `None`
+/// when none has. Unless the signature is `require`d, servers that fail are only
+/// an error when none of them answered.
+pub(crate) async fn download_signature(
+    urls: &[String],
+    require: bool,
+) -> Result<Option<Vec<u8>>, SignatureError> {
+    let client = http_client().map_err(SignatureError::Client)?;
+
+    let mut error = None;
+    let mut answered = false;
+    for url in urls {
+        let res = match client.get(url).send().await {
+            Ok(res) => res,
+            Err(err) => {
+                error = Some(SignatureError::Reqwest(err));
+                continue;
+            }
+        };
+        answered = true;
+        let status = res.status();
+        if status == reqwest::StatusCode::NOT_FOUND {
+            continue;
+        }
+        if !status.is_success() {
+            error = Some(SignatureError::Http(status));
+            continue;
+        }
+
+        match res.bytes().await {
+            Ok(signature) => return Ok(Some(signature.to_vec())),
+            Err(err) => error = Some(SignatureError::Reqwest(err)),
+        }
+    }
+
+    match error {
+        Some(err) if require || !answered => Err(err),
+        _ => Ok(None),
+    }
+}
+
+/// Keys trusted to sign LLVM releases.
+pub(crate) struct Keyring {
+    keys: Vec<SignedPublicKey>,
+}
+
+fn parse_keys(name: &str, s: &str) -> Result<Vec<SignedPublicKey>, SignatureError> {
+    // Text before the first key is a comment
+    let Some(start) = s
+        .match_indices(KEY_BLOCK)
+        .map(|(i, _)| i)
+        .find(|&i| i == 0 || s[..i].ends_with('\n'))
+    else {
+        return Ok(vec![]);
+    };
+
+    let (keys, _) = SignedPublicKey::from_armor_many(&s.as_bytes()[start..])
+        .map_err(|err| SignatureError::Keys(name.into(), err))?;
+    let keys = keys
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| SignatureError::Keys(name.into(), err))?;
+    for key in keys.iter() {
+        key.verify()
+            .map_err(|err| SignatureError::Keys(name.into(), err))?;
+    }
+
+    Ok(keys)
+}
+
+fn verify_file(
+    t: &TaskRef,
+    path: &Path,
+    signature: &pgp::Signature,
+    key: &impl PublicKeyTrait,
+    name: String,
+) -> Result<(), SignatureError> {
+    let f = std::fs::File::open(path).map_err(SignatureError::IO)?;
+    let f = ProgressReader::new(t, f).map_err(SignatureError::IO)?;
+    signature
+        .verify(key, f)
+        .map_err(|err| SignatureError::BadSignature(name, err))
+}
+
+impl Keyring {
+    /// The bundled keys, extended by `~/.config/llvmgr/release-keys.asc` when it exists.
+    pub fn load() -> Result<Keyring, SignatureError> {
+        let mut keyring = Keyring::parse("bundled release keys", BUNDLED_KEYS)?;
+
+        let user_path = config_path("release-keys.asc").map_err(SignatureError::FileSystem)?;
+        if user_path.exists() {
+            let s = std::fs::read_to_string(&user_path).map_err(SignatureError::IO)?;
+            let user = Keyring::parse(&user_path.display().to_string(), &s)?;
+            keyring.keys.extend(user.keys);
+        }
+
+        Ok(keyring)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// The armored keys of `s`, named `name` in errors.
+    pub fn parse(name: &str, s: &str) -> Result<Keyring, SignatureError> {
+        Ok(Keyring {
+            keys: parse_keys(name, s)?,
+        })
+    }
+
+    /// Verifies a detached signature, armored or binary, of the file at `path`.
+    pub fn verify(&self, t: &TaskRef, path: &Path, signature: &[u8]) -> Result<(), SignatureError> {
+        t.set_subtask("verifying signature");
+
+        let name = path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string();
+        if self.keys.is_empty() {
+            return Err(SignatureError::NoKeys);
+        }
+
+        let signature = if signature.starts_with(b"-----BEGIN") {
+            StandaloneSignature::from_armor_single(signature).map(|(signature, _)| signature)
+        } else {
+            StandaloneSignature::from_bytes(signature)
+        }
+        .map_err(SignatureError::Parse)?
+        .signature;
+
+        // Releases may be signed by a subkey
+        let issuers = signature.issuer();
+        for key in self.keys.iter() {
+            if issuers.contains(&&key.key_id()) {
+                return verify_file(t, path, &signature, key, name);
+            }
+            for subkey in key.public_subkeys.iter() {
+                if issuers.contains(&&subkey.key_id()) {
+                    return verify_file(t, path, &signature, subkey, name);
+                }
+            }
+        }
+
+        let issuers = issuers
+            .iter()
+            .map(|issuer| format!("{issuer:X}"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        Err(SignatureError::UnknownKey(name, issuers))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{download_signature, Keyring, SignatureError, BUNDLED_KEYS};
+    use crate::{
+        commands::test_server::{refused_url, Response, TestServer},
+        tasks::Tasks,
+    };
+    use std::path::{Path, PathBuf};
+
+    /// Signed by the primary key of `signer-key.asc` as `.sig`, and by its subkey as `.asc`
+    const SIGNED: &str = "src/commands/llvm/fixtures/signed.txt";
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join(name)
+    }
+
+    fn keyring(name: &str) -> Keyring {
+        let path = fixture(&format!("src/commands/llvm/fixtures/{name}"));
+        Keyring::parse(name, &std::fs::read_to_string(path).unwrap()).unwrap()
+    }
+
+    fn signature(extension: &str) -> Vec<u8> {
+        std::fs::read(fixture(&format!("{SIGNED}.{extension}"))).unwrap()
+    }
+
+    #[test]
+    fn parses_bundled_keys() {
+        Keyring::parse("bundled release keys", BUNDLED_KEYS).unwrap();
+    }
+
+    #[test]
+    #[ignore = "release-keys.asc does not list the keys of the release managers yet"]
+    fn bundles_release_keys() {
+        let keyring = Keyring::parse("bundled release keys", BUNDLED_KEYS).unwrap();
+        assert!(!keyring.is_empty());
+    }
+
+    #[tokio::test]
+    async fn verifies_signed_files() {
+        let mut tasks = Tasks::hidden();
+        let t = tasks.new_task("test").unwrap();
+        let keyring = keyring("signer-key.asc");
+
+        let path = fixture(SIGNED);
+        keyring.verify(&t, &path, &signature("sig")).unwrap();
+        keyring.verify(&t, &path, &signature("asc")).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejects_tampered_files() {
+        let mut tasks = Tasks::hidden();
+        let t = tasks.new_task("test").unwrap();
+        let dir = std::env::temp_dir().join(format!("llvmgr-{}-tampered", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("signed.txt");
+        let mut contents = std::fs::read(fixture(SIGNED)).unwrap();
+        contents[0] ^= 1;
+        std::fs::write(&path, contents).unwrap();
+
+        let keyring = keyring("signer-key.asc");
+        for extension in ["sig", "asc"] {
+            let result = keyring.verify(&t, &path, &signature(extension));
+            assert!(matches!(result, Err(SignatureError::BadSignature(..))));
+        }
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_keys() {
+        let mut tasks = Tasks::hidden();
+        let t = tasks.new_task("test").unwrap();
+        let path = fixture(SIGNED);
+
+        let result = keyring("other-key.asc").verify(&t, &path, &signature("sig"));
+        assert!(matches!(result, Err(SignatureError::UnknownKey(..))));
+        let empty = Keyring::parse("empty", "Only a comment").unwrap();
+        let result = empty.verify(&t, &path, &signature("sig"));
+        assert!(matches!(result, Err(SignatureError::NoKeys)));
+        let result = keyring("signer-key.asc").verify(&t, &path, b"not a signature");
+        assert!(matches!(result, Err(SignatureError::Parse(_))));
+    }
+
+    #[tokio::test]
+    async fn downloads_signatures_from_the_first_server_that_has_them() {
+        let missing = TestServer::start(|_| Response::new(404)).await;
+        let server = TestServer::start(|_| Response::new(200).body(b"signature")).await;
+        let urls = [
+            format!("{}/a.sig", refused_url().await),
+            format!("{}/a.sig", missing.url),
+            format!("{}/a.sig", server.url),
+        ];
+
+        let signature = download_signature(&urls, true).await.unwrap();
+        assert_eq!(signature.as_deref(), Some(b"signature".as_slice()));
+    }
+
+    #[tokio::test]
+    async fn ignores_failing_servers_unless_required() {
+        let failing = TestServer::start(|_| Response::new(500)).await;
+        let missing = TestServer::start(|_| Response::new(404)).await;
+        let urls = [
+            format!("{}/a.sig", failing.url),
+            format!("{}/a.sig", missing.url),
+        ];
+
+        assert!(download_signature(&urls, false).await.unwrap().is_none());
+        assert!(matches!(
+            download_signature(&urls, true).await,
+            Err(SignatureError::Http(
+                reqwest::StatusCode::INTERNAL_SERVER_ERROR
+            ))
+        ));
+    }
+
+    #[tokio::test]
+    async fn fails_when_no_server_answers() {
+        let urls = [format!("{}/a.sig", refused_url().await)];
+        assert!(matches!(
+            download_signature(&urls, false).await,
+            Err(SignatureError::Reqwest(_))
+        ));
+    }
+}
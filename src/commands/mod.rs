@@ -2,20 +2,28 @@ mod llvm;
 
 use std::{
     collections::HashMap,
-    io::{BufRead, Cursor, Read, Seek},
+    io::{BufRead, Read},
     path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
 };
 
 use color_eyre::{eyre::Context, Help, Report};
-use fs_extra::dir::CopyOptions;
-use reqwest::IntoUrl;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
 
 use crate::tasks::TaskRef;
+use http::{http_client, HttpClientError};
 
+pub(crate) mod config;
+pub(crate) mod http;
 pub(crate) mod install;
+pub(crate) mod list;
+#[cfg(test)]
+mod test_server;
 
 #[derive(Error, Debug)]
 pub(crate) enum FileSystemError {
@@ -23,10 +31,6 @@ pub(crate) enum FileSystemError {
     UserDirError,
     #[error("{0}")]
     IO(std::io::Error),
-    #[error("{0}")]
-    CannotMove(fs_extra::error::Error),
-    #[error("{0}")]
-    CannotRemove(fs_extra::error::Error),
 }
 
 fn cache_root() -> Result<PathBuf, FileSystemError> {
@@ -49,34 +53,351 @@ pub(crate) fn cache_path(path: impl AsRef<Path>) -> Result<PathBuf, FileSystemEr
     Ok(p)
 }
 
+pub(crate) fn config_path(path: impl AsRef<Path>) -> Result<PathBuf, FileSystemError> {
+    let dirs = directories::UserDirs::new().ok_or(FileSystemError::UserDirError)?;
+    let p = dirs.home_dir().join(".config/llvmgr").join(path);
+    Ok(p)
+}
+
 fn set_current_dir_inside_cache_folder(path: impl AsRef<Path>) -> Result<(), FileSystemError> {
     let p = dir_inside_cache_folder(path)?;
     std::env::set_current_dir(p).map_err(FileSystemError::IO)
 }
 
+/// Smaller files are downloaded over a single connection
+const CHUNKED_DOWNLOAD_MIN_LENGTH: u64 = 16 * 1024 * 1024;
+
+/// Bytes written to a chunk between saves of its progress
+const CHUNK_PROGRESS_INTERVAL: u64 = 1024 * 1024;
+
+const DEFAULT_MIRROR_TEMPLATE: &str = "{base}/llvmorg-{version}/{file}";
+
+/// Mirrors of the release artifacts, tried in order before the original url.
+#[derive(Default)]
+pub(crate) struct Mirrors {
+    templates: Vec<String>,
+}
+
+impl Mirrors {
+    /// A mirror is either the `{base}` of `template`, or a template of its own when it
+    /// has placeholders. `LLVMGR_MIRROR`, a comma separated list, takes the place of
+    /// the configured mirrors.
+    pub fn new(mirrors: &[String], template: Option<&str>) -> Mirrors {
+        Mirrors::with_env(std::env::var("LLVMGR_MIRROR").ok(), mirrors, template)
+    }
+
+    /// Mirrors, with `env` as the value of `LLVMGR_MIRROR`.
+    fn with_env(env: Option<String>, mirrors: &[String], template: Option<&str>) -> Mirrors {
+        let template = template.unwrap_or(DEFAULT_MIRROR_TEMPLATE);
+        let mirrors = match env {
+            Some(env) => env
+                .split(',')
+                .map(str::trim)
+                .filter(|mirror| !mirror.is_empty())
+                .map(str::to_string)
+                .collect(),
+            None => mirrors.to_vec(),
+        };
+
+        let templates = mirrors
+            .iter()
+            .map(|mirror| {
+                if mirror.contains('{') {
+                    mirror.clone()
+                } else {
+                    template.replace("{base}", mirror.trim_end_matches('/'))
+                }
+            })
+            .collect();
+        Mirrors { templates }
+    }
+
+    /// Hosts of the mirrors
+    pub fn hosts(&self) -> Vec<String> {
+        self.templates
+            .iter()
+            .filter_map(|template| {
+                let url = template
+                    .replace("{version}", "0.0.0")
+                    .replace("{file}", "file");
+                reqwest::Url::parse(&url)
+                    .ok()?
+                    .host_str()
+                    .map(str::to_string)
+            })
+            .collect()
+    }
+
+    /// Urls to download `url` from, in order: the mirrors, then `url` itself.
+    pub fn urls(&self, url: &str, version: &Version) -> Vec<String> {
+        let file = url.rsplit('/').next().unwrap_or_default();
+        let mut urls = self
+            .templates
+            .iter()
+            .map(|template| {
+                template
+                    .replace("{version}", &version.to_string())
+                    .replace("{file}", file)
+            })
+            .collect::<Vec<_>>();
+        urls.push(url.to_string());
+        urls
+    }
+}
+
+fn format_mirror_errors(errors: &[(String, DownloadError)]) -> String {
+    errors
+        .iter()
+        .map(|(url, err)| format!("{url}: {err}"))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
 pub struct DownloadResult {
     path: PathBuf,
 }
 
 #[derive(Error, Debug)]
 pub(crate) enum DownloadError {
-    #[error("cache unavailable: {0}")]
-    CacheUnavailable(FileSystemError),
     #[error("url {0}")]
     InvalidUrl(String),
+    #[error("{0}")]
+    Client(HttpClientError),
     #[error("http error")]
     Reqwest(reqwest::Error),
-    #[error("http error")]
+    #[error("http error {0}")]
     Http(reqwest::StatusCode),
     #[error("Content-Length header {0}")]
     ContentLength(String),
+    #[error("Content-Range header {0}")]
+    ContentRange(String),
+    #[error("received {0} of {1} bytes")]
+    Incomplete(u64, u64),
+    #[error("sha256 of {0} is {2}, expected {1}")]
+    ChecksumMismatch(PathBuf, String, String),
     #[error("io error")]
     IO(tokio::io::Error),
+    #[error("{}", format_mirror_errors(.0))]
+    Mirrors(Vec<(String, DownloadError)>),
+}
+
+// parses strings like: "bytes 100-199/200" or "bytes 100-199/*"
+fn parse_content_range(s: &str) -> nom::IResult<&str, (u64, Option<u64>)> {
+    use nom::{
+        branch::alt,
+        bytes::complete::tag,
+        character::complete::{digit1, space1},
+        combinator::{map, map_opt},
+    };
+
+    let (s, _) = tag("bytes")(s)?;
+    let (s, _) = space1(s)?;
+    let (s, start) = map_opt(digit1, |s: &str| s.parse::<u64>().ok())(s)?;
+    let (s, _) = tag("-")(s)?;
+    let (s, _) = digit1(s)?;
+    let (s, _) = tag("/")(s)?;
+    let (s, total) = alt((
+        map(tag("*"), |_| None),
+        map_opt(digit1, |s: &str| s.parse::<u64>().ok().map(Some)),
+    ))(s)?;
+
+    Ok((s, (start, total)))
+}
+
+/// What the server said about a file whose download was interrupted, so it is only
+/// resumed if the file did not change since.
+#[derive(Default, Serialize, Deserialize)]
+struct PartialDownload {
+    /// Server it was downloaded from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+    etag: Option<String>,
+    last_modified: Option<String>,
+    /// Length of the whole file
+    content_length: Option<u64>,
+    /// When downloaded over several connections
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    chunks: Vec<ChunkProgress>,
+}
+
+/// Bytes `start..end` of a file downloaded in chunks, of which `written` are saved.
+#[derive(Clone, Serialize, Deserialize)]
+struct ChunkProgress {
+    start: u64,
+    end: u64,
+    written: u64,
+}
+
+impl ChunkProgress {
+    /// Where the chunk continues
+    fn position(&self) -> u64 {
+        self.start + self.written
+    }
+}
+
+impl PartialDownload {
+    fn from_headers(
+        url: &reqwest::Url,
+        headers: &reqwest::header::HeaderMap,
+        content_length: Option<u64>,
+    ) -> Self {
+        let header = |name| {
+            headers
+                .get(name)
+                .and_then(|value: &reqwest::header::HeaderValue| value.to_str().ok())
+                .map(str::to_string)
+        };
+        PartialDownload {
+            url: Some(url.to_string()),
+            etag: header(reqwest::header::ETAG),
+            last_modified: header(reqwest::header::LAST_MODIFIED),
+            content_length,
+            chunks: vec![],
+        }
+    }
+
+    /// Value for the `If-Range` header. Weak ETags cannot be used.
+    fn validator(&self) -> Option<&str> {
+        self.etag
+            .as_deref()
+            .filter(|etag| !etag.starts_with("W/"))
+            .or(self.last_modified.as_deref())
+    }
 }
 
+/// Downloads into `dir`, the cache folder, reusing what was downloaded before.
+///
+/// `urls` are tried in order, falling back to the next one when a server answers
+/// with an error or cannot be reached. When `sha256` is given, a file with another
+/// digest is moved to `~/.cache/llvmgr/quarantine` instead of being returned. Without
+/// it, the task warns that the file was not verified.
 async fn download(
+    t: &TaskRef,
+    urls: &[String],
+    sha256: Option<&str>,
+    dir: &Path,
+) -> Result<DownloadResult, DownloadError> {
+    let mut errors = vec![];
+    let mut downloaded = None;
+    for url in urls {
+        match fetch(t, url.as_str(), dir).await {
+            Ok(result) => {
+                downloaded = Some(result);
+                break;
+            }
+            Err(err @ (DownloadError::Http(_) | DownloadError::Reqwest(_))) => {
+                errors.push((url.clone(), err));
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    let downloaded = match downloaded {
+        Some(downloaded) => downloaded,
+        None if errors.len() == 1 => return Err(errors.remove(0).1),
+        None => return Err(DownloadError::Mirrors(errors)),
+    };
+
+    match sha256 {
+        Some(expected) => match verify_sha256(t, &downloaded.path, expected) {
+            Err(DownloadError::ChecksumMismatch(path, expected, actual)) => {
+                let quarantine = quarantine(&path)?;
+                return Err(DownloadError::ChecksumMismatch(
+                    quarantine, expected, actual,
+                ));
+            }
+            result => result?,
+        },
+        None => t.warn("no known sha256, the download was not verified"),
+    }
+
+    Ok(downloaded)
+}
+
+/// Moves a download that failed verification to the `quarantine` folder next to it,
+/// `~/.cache/llvmgr/quarantine`, so it is not reused.
+pub(crate) fn quarantine(path: &Path) -> Result<PathBuf, DownloadError> {
+    let dir = path
+        .parent()
+        .unwrap_or_else(|| Path::new("."))
+        .join("quarantine");
+    std::fs::create_dir_all(&dir).map_err(DownloadError::IO)?;
+    let quarantine = dir.join(path.file_name().unwrap_or_default());
+    std::fs::rename(path, &quarantine).map_err(DownloadError::IO)?;
+    Ok(quarantine)
+}
+
+/// Reports how much of a file was read as the percentage of a task.
+pub(crate) struct ProgressReader<'a, R> {
+    t: &'a TaskRef,
+    inner: R,
+    read: u64,
+    total: f64,
+}
+
+impl<'a> ProgressReader<'a, std::fs::File> {
+    pub fn new(t: &'a TaskRef, f: std::fs::File) -> Result<Self, std::io::Error> {
+        let total = f.metadata()?.len() as f64;
+        Ok(ProgressReader {
+            t,
+            inner: f,
+            read: 0,
+            total,
+        })
+    }
+}
+
+impl<R: Read> Read for ProgressReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let s = self.inner.read(buf)?;
+        self.read += s as u64;
+        self.t.set_percentage(self.read as f64 / self.total);
+        Ok(s)
+    }
+}
+
+/// Progress of a seeking reader is where it is in the file.
+impl<R: std::io::Seek> std::io::Seek for ProgressReader<'_, R> {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        self.read = self.inner.seek(pos)?;
+        self.t.set_percentage(self.read as f64 / self.total);
+        Ok(self.read)
+    }
+}
+
+/// Fails unless the SHA-256 digest of the file is `expected`, as lowercase hex.
+pub(crate) fn verify_sha256(
+    t: &TaskRef,
+    path: impl AsRef<Path>,
+    expected: &str,
+) -> Result<(), DownloadError> {
+    use sha2::Digest;
+
+    t.set_subtask("verifying");
+
+    let path = path.as_ref();
+    let f = std::fs::File::open(path).map_err(DownloadError::IO)?;
+    let mut f = ProgressReader::new(t, f).map_err(DownloadError::IO)?;
+    let mut hasher = sha2::Sha256::new();
+    std::io::copy(&mut f, &mut hasher).map_err(DownloadError::IO)?;
+
+    let actual = format!("{:x}", hasher.finalize());
+    if actual != expected.trim().to_ascii_lowercase() {
+        return Err(DownloadError::ChecksumMismatch(
+            path.to_path_buf(),
+            expected.into(),
+            actual,
+        ));
+    }
+
+    Ok(())
+}
+
+/// The file is written as `<name>.part` and renamed when complete. An interrupted
+/// download is resumed with a `Range` request, if the server tells the file did not change.
+async fn fetch(
     t: &TaskRef,
     url: impl reqwest::IntoUrl,
+    dir: &Path,
 ) -> Result<DownloadResult, DownloadError> {
     t.set_subtask("downloading");
 
@@ -87,229 +408,917 @@ async fn download(
     let file_name = url
         .path_segments()
         .ok_or_else(|| DownloadError::InvalidUrl("url does not have segments".to_string()))?
-        .last()
+        .next_back()
         .ok_or_else(|| DownloadError::InvalidUrl("url does not have segments".to_string()))?;
 
-    let cache_root = cache_root().map_err(DownloadError::CacheUnavailable)?;
-    let cache_file_path = cache_root.join(file_name);
+    let cache_file_path = dir.join(file_name);
     if cache_file_path.exists() {
         return Ok(DownloadResult {
             path: cache_file_path,
         });
     }
 
-    let mut cache_file = tokio::fs::OpenOptions::new()
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .open(&cache_file_path)
-        .await
-        .map_err(DownloadError::IO)?;
+    let part_path = dir.join(format!("{file_name}.part"));
+    let partial_path = dir.join(format!("{file_name}.part.json"));
+    let partial = std::fs::read_to_string(&partial_path)
+        .ok()
+        .and_then(|json| serde_json::from_str::<PartialDownload>(&json).ok())
+        .unwrap_or_default();
+    let part_len = std::fs::metadata(&part_path)
+        .map(|metadata| metadata.len())
+        .ok();
+
+    // Every chunk was downloaded, but not renamed
+    if !partial.chunks.is_empty()
+        && partial.content_length.is_some()
+        && partial.content_length == part_len
+        && partial
+            .chunks
+            .iter()
+            .all(|chunk| chunk.position() == chunk.end)
+    {
+        std::fs::rename(&part_path, &cache_file_path).map_err(DownloadError::IO)?;
+        let _ = std::fs::remove_file(&partial_path);
+        return Ok(DownloadResult {
+            path: cache_file_path,
+        });
+    }
 
-    let req = reqwest::get(url).await.map_err(DownloadError::Reqwest)?;
+    // Chunks are written in place, into a file of the whole length
+    let resume = match (partial.validator(), part_len) {
+        (Some(_), Some(len)) if partial.chunks.is_empty() && len > 0 => Some((len, None)),
+        (Some(_), Some(len)) if partial.content_length == Some(len) => partial
+            .chunks
+            .iter()
+            .find(|chunk| chunk.position() < chunk.end)
+            .map(|chunk| (chunk.position(), Some(chunk.end))),
+        _ => None,
+    };
+    let downloaded = resume.map_or(0, |(start, _)| start);
+    // A mirror that does not have the file does not discard what another server sent
+    let discard = partial
+        .url
+        .as_deref()
+        .is_none_or(|source| source == url.as_str());
+
+    let mut req = http_client()
+        .map_err(DownloadError::Client)?
+        .get(url.as_str());
+    if let (Some(validator), Some((start, end))) = (partial.validator(), resume) {
+        let end = end.map(|end| (end - 1).to_string()).unwrap_or_default();
+        req = req
+            .header(reqwest::header::RANGE, format!("bytes={start}-{end}"))
+            .header(reqwest::header::IF_RANGE, validator);
+    }
+    let req = req.send().await.map_err(DownloadError::Reqwest)?;
     let status = req.status();
+
+    // Everything was downloaded, but not renamed
+    if status == reqwest::StatusCode::RANGE_NOT_SATISFIABLE
+        && partial.content_length == Some(downloaded)
+    {
+        std::fs::rename(&part_path, &cache_file_path).map_err(DownloadError::IO)?;
+        let _ = std::fs::remove_file(&partial_path);
+        return Ok(DownloadResult {
+            path: cache_file_path,
+        });
+    }
     if !status.is_success() {
+        // What was downloaded is kept until the server is back
+        if !status.is_server_error() && discard {
+            let _ = std::fs::remove_file(&part_path);
+            let _ = std::fs::remove_file(&partial_path);
+        }
         return Err(DownloadError::Http(status));
     }
 
-    let content_length = req
-        .headers()
-        .get(reqwest::header::CONTENT_LENGTH)
-        .and_then(|x| x.to_str().ok()?.parse::<f64>().ok());
+    let (offset, content_length) = if status == reqwest::StatusCode::PARTIAL_CONTENT {
+        let content_range = req
+            .headers()
+            .get(reqwest::header::CONTENT_RANGE)
+            .and_then(|x| x.to_str().ok())
+            .unwrap_or_default();
+        match parse_content_range(content_range) {
+            Ok((_, (start, total)))
+                if start == downloaded && (total.is_none() || total == partial.content_length) =>
+            {
+                if let (Some(content_length), false) =
+                    (partial.content_length, partial.chunks.is_empty())
+                {
+                    fetch_chunks(
+                        t,
+                        &url,
+                        req,
+                        partial,
+                        content_length,
+                        &part_path,
+                        &partial_path,
+                    )
+                    .await?;
+                    std::fs::rename(&part_path, &cache_file_path).map_err(DownloadError::IO)?;
+                    let _ = std::fs::remove_file(&partial_path);
+                    return Ok(DownloadResult {
+                        path: cache_file_path,
+                    });
+                }
+                (downloaded, partial.content_length)
+            }
+            _ => {
+                if discard {
+                    let _ = std::fs::remove_file(&part_path);
+                    let _ = std::fs::remove_file(&partial_path);
+                }
+                return Err(DownloadError::ContentRange(content_range.to_string()));
+            }
+        }
+    } else {
+        let content_length = match req.headers().get(reqwest::header::CONTENT_LENGTH) {
+            Some(x) => {
+                let x = x.to_str().unwrap_or_default();
+                Some(
+                    x.parse::<u64>()
+                        .map_err(|_| DownloadError::ContentLength(x.to_string()))?,
+                )
+            }
+            None => None,
+        };
+
+        let connections = http_client().map_err(DownloadError::Client)?.connections();
+        let accepts_ranges = req
+            .headers()
+            .get(reqwest::header::ACCEPT_RANGES)
+            .is_some_and(|x| x.as_bytes() == b"bytes");
+        if let Some(content_length) = content_length
+            .filter(|content_length| *content_length >= CHUNKED_DOWNLOAD_MIN_LENGTH)
+            .filter(|_| connections > 1 && accepts_ranges)
+        {
+            let f = std::fs::File::create(&part_path).map_err(DownloadError::IO)?;
+            f.set_len(content_length).map_err(DownloadError::IO)?;
+
+            let mut partial =
+                PartialDownload::from_headers(&url, req.headers(), Some(content_length));
+            let chunk_length = content_length.div_ceil(connections as u64);
+            partial.chunks = (0..content_length)
+                .step_by(chunk_length as usize)
+                .map(|start| ChunkProgress {
+                    start,
+                    end: (start + chunk_length).min(content_length),
+                    written: 0,
+                })
+                .collect();
+            fetch_chunks(
+                t,
+                &url,
+                req,
+                partial,
+                content_length,
+                &part_path,
+                &partial_path,
+            )
+            .await?;
+
+            std::fs::rename(&part_path, &cache_file_path).map_err(DownloadError::IO)?;
+            let _ = std::fs::remove_file(&partial_path);
+            return Ok(DownloadResult {
+                path: cache_file_path,
+            });
+        }
+
+        let partial = PartialDownload::from_headers(&url, req.headers(), content_length);
+        let json = serde_json::to_string_pretty(&partial).expect("this should not fail");
+        std::fs::write(&partial_path, json).map_err(DownloadError::IO)?;
+        (0, content_length)
+    };
+
+    let mut cache_file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .append(offset > 0)
+        .truncate(offset == 0)
+        .open(&part_path)
+        .await
+        .map_err(DownloadError::IO)?;
 
     use futures_util::StreamExt;
-    let mut completed = 0.0;
+    let mut completed = offset;
     let mut stream = req.bytes_stream();
     while let Some(item) = stream.next().await {
-        let bytes = item.map_err(DownloadError::Reqwest)?;
+        let bytes = match item {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                // Writes still in flight would land after a resume measured the file
+                let _ = cache_file.flush().await;
+                return Err(DownloadError::Reqwest(err));
+            }
+        };
         cache_file
             .write_all(&bytes)
             .await
             .map_err(DownloadError::IO)?;
+        completed += bytes.len() as u64;
 
-        if let Some(content_length) = content_length.as_ref() {
-            completed += bytes.len() as f64 / content_length;
-            t.set_percentage(completed)
+        if let Some(content_length) = content_length {
+            t.set_percentage(completed as f64 / content_length as f64)
         } else {
-            completed += bytes.len() as f64;
             t.set_subtask(&format!("{} bytes", completed));
         }
     }
+    cache_file.flush().await.map_err(DownloadError::IO)?;
+
+    if let Some(content_length) = content_length {
+        if completed != content_length {
+            return Err(DownloadError::Incomplete(completed, content_length));
+        }
+    }
+
+    std::fs::rename(&part_path, &cache_file_path).map_err(DownloadError::IO)?;
+    let _ = std::fs::remove_file(&partial_path);
 
     Ok(DownloadResult {
         path: cache_file_path,
     })
 }
 
-#[derive(Error, Debug)]
-pub(crate) enum UnxzError {
-    #[error("{0}")]
-    IO(std::io::Error),
-}
-
-pub(crate) async fn ungz(t: &TaskRef, path: impl AsRef<Path>) -> Result<Vec<u8>, std::io::Error> {
-    t.set_subtask("ungz-ing");
+/// Downloads the chunks of `partial` that are missing bytes over range requests at
+/// once, into `part_path`, preallocated to the length of the file. The first of them is
+/// read from `res`, the response that told the server accepts ranges.
+///
+/// Progress is saved to `partial_path` as chunks are written, so an interrupted download
+/// only requests the missing bytes when it is resumed.
+async fn fetch_chunks(
+    t: &TaskRef,
+    url: &reqwest::Url,
+    res: reqwest::Response,
+    partial: PartialDownload,
+    content_length: u64,
+    part_path: &Path,
+    partial_path: &Path,
+) -> Result<(), DownloadError> {
+    let mut res = Some(res);
+    let chunks = partial
+        .chunks
+        .iter()
+        .enumerate()
+        .filter(|(_, chunk)| chunk.position() < chunk.end)
+        .map(|(index, chunk)| Chunk {
+            index,
+            start: chunk.position(),
+            end: chunk.end,
+            res: res.take(),
+        })
+        .collect::<Vec<_>>();
+
+    let download = ChunkedDownload {
+        url,
+        // Ranges are only downloaded from the same file as the first response
+        validator: partial.validator().map(str::to_string),
+        content_length,
+        completed: AtomicU64::new(partial.chunks.iter().map(|chunk| chunk.written).sum()),
+        part_path,
+        partial_path,
+        progress: Mutex::new(partial),
+    };
+    download.save()?;
 
-    let f = std::fs::File::options().read(true).open(path)?;
+    // Every chunk goes on when another fails, to save as much as possible
+    let chunks = chunks
+        .into_iter()
+        .map(|chunk| fetch_chunk(t, &download, chunk));
+    let result = futures_util::future::join_all(chunks)
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>, _>>();
 
-    let metadata = f.metadata()?;
-    let total = metadata.len() as f64;
+    // Without a validator, a download cannot be resumed
+    if result.is_err() && download.validator.is_none() {
+        let _ = std::fs::remove_file(part_path);
+        let _ = std::fs::remove_file(partial_path);
+    }
+    result.map(|_| ())
+}
 
-    let mut f = libflate::gzip::Decoder::new(f)?;
+/// Bytes `start..end` of a file, the `index`th chunk of its download, with the response
+/// they are read from when it was already requested.
+struct Chunk {
+    index: usize,
+    start: u64,
+    end: u64,
+    res: Option<reqwest::Response>,
+}
 
-    let mut out = vec![];
+/// What the chunks of a download share.
+struct ChunkedDownload<'a> {
+    url: &'a reqwest::Url,
+    validator: Option<String>,
+    content_length: u64,
+    /// Bytes written by every chunk
+    completed: AtomicU64,
+    part_path: &'a Path,
+    partial_path: &'a Path,
+    progress: Mutex<PartialDownload>,
+}
 
-    let mut buffer = [0u8; 16 * 1024];
-    loop {
-        let s = f.read(&mut buffer)?;
-        if s == 0 {
-            break;
+impl ChunkedDownload<'_> {
+    /// Saves that the `index`th chunk was written up to `position`.
+    fn save_chunk(&self, index: usize, position: u64) -> Result<(), DownloadError> {
+        {
+            let mut partial = self.progress.lock().expect("should not be poisoned");
+            let chunk = &mut partial.chunks[index];
+            chunk.written = position - chunk.start;
         }
-        out.extend(&buffer[0..s]);
-
-        let inner_pos = f.as_inner_ref().seek(std::io::SeekFrom::Current(0))?;
-        t.set_percentage(inner_pos as f64 / total)
+        self.save()
     }
 
-    Ok(out)
+    fn save(&self) -> Result<(), DownloadError> {
+        let partial = self.progress.lock().expect("should not be poisoned");
+        let json = serde_json::to_string_pretty(&*partial).expect("this should not fail");
+        std::fs::write(self.partial_path, json).map_err(DownloadError::IO)
+    }
 }
 
-pub(crate) async fn unxz(t: &TaskRef, path: impl AsRef<Path>) -> Result<Vec<u8>, UnxzError> {
-    t.set_subtask("unxz-ing");
+async fn fetch_chunk(
+    t: &TaskRef,
+    download: &ChunkedDownload<'_>,
+    chunk: Chunk,
+) -> Result<(), DownloadError> {
+    let ChunkedDownload {
+        url,
+        content_length,
+        part_path,
+        ..
+    } = *download;
+    let Chunk {
+        index,
+        start,
+        end,
+        res,
+    } = chunk;
+    let res = match res {
+        Some(res) => res,
+        None => {
+            let mut req = http_client()
+                .map_err(DownloadError::Client)?
+                .get(url.as_str())
+                .header(reqwest::header::RANGE, format!("bytes={start}-{}", end - 1));
+            if let Some(validator) = download.validator.as_deref() {
+                req = req.header(reqwest::header::IF_RANGE, validator);
+            }
+            let res = req.send().await.map_err(DownloadError::Reqwest)?;
+            if !res.status().is_success() {
+                return Err(DownloadError::Http(res.status()));
+            }
 
-    let f = std::fs::File::options()
-        .read(true)
-        .open(path)
-        .map_err(UnxzError::IO)?;
-    let metadata = f.metadata().map_err(UnxzError::IO)?;
+            let content_range = res
+                .headers()
+                .get(reqwest::header::CONTENT_RANGE)
+                .and_then(|x| x.to_str().ok())
+                .unwrap_or_default();
+            match parse_content_range(content_range) {
+                Ok((_, (range_start, total)))
+                    if res.status() == reqwest::StatusCode::PARTIAL_CONTENT
+                        && range_start == start
+                        && (total.is_none() || total == Some(content_length)) => {}
+                _ => return Err(DownloadError::ContentRange(content_range.to_string())),
+            }
+            res
+        }
+    };
 
-    let total = metadata.len() as f64;
+    let mut f = tokio::fs::OpenOptions::new()
+        .write(true)
+        .open(part_path)
+        .await
+        .map_err(DownloadError::IO)?;
+    f.seek(std::io::SeekFrom::Start(start))
+        .await
+        .map_err(DownloadError::IO)?;
 
-    let mut f = xz2::read::XzDecoder::new(f);
+    use futures_util::StreamExt;
+    let mut written = start;
+    let mut saved = start;
+    let mut stream = res.bytes_stream();
+    let result = loop {
+        if written >= end {
+            break Ok(());
+        }
+        let bytes = match stream.next().await {
+            Some(Ok(bytes)) => bytes,
+            Some(Err(err)) => break Err(DownloadError::Reqwest(err)),
+            None => break Err(DownloadError::Incomplete(written - start, end - start)),
+        };
+        // The first response goes on past its range
+        let bytes = &bytes[..bytes.len().min((end - written) as usize)];
+        if let Err(err) = f.write_all(bytes).await {
+            break Err(DownloadError::IO(err));
+        }
+        written += bytes.len() as u64;
 
-    let mut out = vec![];
+        let completed = download
+            .completed
+            .fetch_add(bytes.len() as u64, Ordering::Relaxed)
+            + bytes.len() as u64;
+        t.set_percentage(completed as f64 / content_length as f64);
 
-    let mut buffer = [0u8; 16 * 1024];
-    loop {
-        let s = f.read(&mut buffer).map_err(UnxzError::IO)?;
-        if s == 0 {
-            break;
+        if written - saved >= CHUNK_PROGRESS_INTERVAL {
+            if let Err(err) = f.flush().await {
+                break Err(DownloadError::IO(err));
+            }
+            download.save_chunk(index, written)?;
+            saved = written;
         }
-        out.extend(&buffer[0..s]);
-
-        t.set_percentage(f.total_in() as f64 / total)
-    }
+    };
 
-    Ok(out)
+    // What was written before an error is kept for the next attempt
+    f.flush().await.map_err(DownloadError::IO)?;
+    download.save_chunk(index, written)?;
+    result
 }
 
 #[derive(Error, Debug)]
-pub(crate) enum UntarError {
+pub(crate) enum ExtractError {
     #[error("invalid destination")]
     InvalidDest,
+    #[error("{0} is a link without a target")]
+    MissingLinkTarget(PathBuf),
+    #[error("{0} points outside of the destination")]
+    OutsideDest(PathBuf),
     #[error("{0} {1}")]
     IO(&'static str, std::io::Error),
+    #[error("{0}")]
+    Zip(zip::result::ZipError),
 }
 
-pub(crate) fn untar_from_vec(
-    t: &TaskRef,
-    v: Vec<u8>,
-    dest: impl AsRef<Path>,
-) -> Result<(), UntarError> {
-    t.set_subtask("untar-ing");
+/// `path` without the folder archives wrap their content in, or `None` when `path` is
+/// absolute or has `..`.
+fn strip_first_component(path: &Path) -> Option<PathBuf> {
+    use std::path::Component;
+
+    let relative = path
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+    relative.then(|| path.components().skip(1).collect())
+}
 
-    let dest = dest.as_ref();
-    std::fs::create_dir_all(dest).map_err(|err| UntarError::IO("create_dir_all dest", err))?;
+/// Creates the folders of `rel_path` inside `dest`, which must be canonical, and
+/// returns where `rel_path` is once the symlinks on the way are followed.
+///
+/// Fails instead of following a symlink outside of `dest`.
+fn create_dir_inside(dest: &Path, rel_path: &Path) -> Result<PathBuf, ExtractError> {
+    let mut dir = dest.to_path_buf();
+    for component in rel_path.components() {
+        let next = dir.join(component);
+        match std::fs::symlink_metadata(&next) {
+            Ok(metadata) if metadata.is_symlink() => {
+                dir = next
+                    .canonicalize()
+                    .map_err(|err| ExtractError::IO("canonicalize", err))?;
+                if !dir.starts_with(dest) {
+                    return Err(ExtractError::OutsideDest(rel_path.to_path_buf()));
+                }
+            }
+            Ok(_) => dir = next,
+            Err(_) => {
+                std::fs::create_dir(&next).map_err(|err| ExtractError::IO("create_dir", err))?;
+                dir = next;
+            }
+        }
+    }
+    Ok(dir)
+}
+
+/// Whether a symlink in `dir`, a folder inside `dest`, to `target` stays inside `dest`.
+///
+/// Only leading `..` are accepted, so the symlinks `target` goes through, which
+/// were checked the same way, cannot lead outside either.
+fn symlink_inside(dest: &Path, dir: &Path, target: &Path) -> bool {
+    use std::path::Component;
+
+    let Ok(dir) = dir.strip_prefix(dest) else {
+        return false;
+    };
+    let mut depth = dir.components().count();
+    let mut components = target.components().peekable();
+    while components.peek() == Some(&Component::ParentDir) {
+        if depth == 0 {
+            return false;
+        }
+        depth -= 1;
+        components.next();
+    }
+    components.all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+}
 
-    // Get how many entries are
-    let v = Cursor::new(v);
-    let mut f = tar::Archive::new(v);
-    let entries = f.entries().map_err(|err| UntarError::IO("entries", err))?;
-    let len = entries.count() as f64;
+/// Extracts the entries of a tar stream into `dest`, as they are read.
+///
+/// Symlinks, hardlinks, permission modes and mtimes are preserved. Entries that would
+/// be written outside of `dest`, or links that point outside of it, are rejected.
+pub(crate) fn untar(r: impl Read, dest: impl AsRef<Path>) -> Result<(), ExtractError> {
+    let dest = canonical_dest(dest.as_ref())?;
 
-    // actualy read the entries
-    let v = f.into_inner().into_inner();
-    let v = Cursor::new(v);
-    let mut f = tar::Archive::new(v);
+    // Modes and mtimes of folders are set once their content is extracted
+    let mut dirs = vec![];
+    let mut link_copies = vec![];
 
-    for (i, entry) in f
+    let mut f = tar::Archive::new(r);
+    for entry in f
         .entries()
-        .map_err(|err| UntarError::IO("entries", err))?
-        .enumerate()
+        .map_err(|err| ExtractError::IO("entries", err))?
     {
-        let mut entry = entry.map_err(|err| UntarError::IO("entry", err))?;
+        let mut entry = entry.map_err(|err| ExtractError::IO("entry", err))?;
+
+        let path = entry
+            .path()
+            .map_err(|err| ExtractError::IO("path", err))?
+            .into_owned();
+        let rel_path =
+            strip_first_component(&path).ok_or_else(|| ExtractError::OutsideDest(path.clone()))?;
+        if rel_path.as_os_str().is_empty() {
+            continue;
+        }
 
-        if !entry.header().entry_type().is_file() {
+        let kind = entry.header().entry_type();
+        if kind.is_dir() {
+            let dir = create_dir_inside(&dest, &rel_path)?;
+            let header = entry.header();
+            dirs.push((dir, header.mode().ok(), header.mtime().ok()));
             continue;
         }
 
-        let path = entry.path().map_err(|err| UntarError::IO("path", err))?;
-        let rel_path = path.components().skip(1);
+        let file_name = rel_path.file_name().ok_or(ExtractError::InvalidDest)?;
+        let parent = create_dir_inside(&dest, rel_path.parent().unwrap_or(Path::new("")))?;
+        let dest_path = parent.join(file_name);
+
+        if kind.is_hard_link() {
+            // Targets are paths inside the archive, so they are stripped the same way
+            let target = entry
+                .link_name()
+                .map_err(|err| ExtractError::IO("link_name", err))?
+                .ok_or_else(|| ExtractError::MissingLinkTarget(path.clone()))?;
+            let target = strip_first_component(&target)
+                .map(|target| dest.join(target))
+                .and_then(|target| target.canonicalize().ok())
+                .filter(|target| target.starts_with(&dest))
+                .ok_or_else(|| ExtractError::OutsideDest(path.clone()))?;
+
+            let _ = std::fs::remove_file(&dest_path);
+            if let Err(err) = std::fs::hard_link(&target, &dest_path) {
+                // Some file systems have no hardlinks
+                std::fs::copy(&target, &dest_path)
+                    .map_err(|_| ExtractError::IO("hard_link", err))?;
+            }
+        } else if kind.is_symlink() {
+            let target = entry
+                .link_name()
+                .map_err(|err| ExtractError::IO("link_name", err))?
+                .ok_or_else(|| ExtractError::MissingLinkTarget(path.clone()))?;
+            if !symlink_inside(&dest, &parent, &target) {
+                return Err(ExtractError::OutsideDest(path));
+            }
+
+            let _ = std::fs::remove_file(&dest_path);
+            extract_symlink(&target, &parent, &dest_path, &mut link_copies)?;
+        } else if kind.is_file() {
+            entry
+                .unpack(&dest_path)
+                .map_err(|err| ExtractError::IO("unpack", err))?;
+        }
+    }
+
+    copy_link_targets(link_copies)?;
+    set_dirs_metadata(&dirs)
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, path: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(target, path)
+}
+
+#[cfg(windows)]
+fn symlink(target: &Path, path: &Path) -> std::io::Result<()> {
+    let resolved = path.parent().unwrap_or(Path::new("")).join(target);
+    if resolved.is_dir() {
+        std::os::windows::fs::symlink_dir(target, path)
+    } else {
+        std::os::windows::fs::symlink_file(target, path)
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+fn symlink(_: &Path, _: &Path) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "symlinks are not supported",
+    ))
+}
+
+/// A symlink that could not be created, to replace by a copy of its target.
+struct LinkCopy {
+    path: PathBuf,
+    target: PathBuf,
+    err: std::io::Error,
+}
 
-        let mut dest = dest.to_path_buf();
-        for p in rel_path {
-            dest.push(p);
+/// Creates a symlink at `path`, in the folder `dir`, to `target`.
+///
+/// Creating symlinks needs privileges on Windows, so when it fails there, the link is
+/// pushed to `copies` to be replaced by its target once everything is extracted.
+fn extract_symlink(
+    target: &Path,
+    dir: &Path,
+    path: &Path,
+    copies: &mut Vec<LinkCopy>,
+) -> Result<(), ExtractError> {
+    match symlink(target, path) {
+        Ok(()) => Ok(()),
+        Err(err) if cfg!(windows) => {
+            copies.push(LinkCopy {
+                path: path.to_path_buf(),
+                target: dir.join(target),
+                err,
+            });
+            Ok(())
         }
+        Err(err) => Err(ExtractError::IO("symlink", err)),
+    }
+}
 
-        let parent = dest.parent().ok_or(UntarError::InvalidDest)?;
-        std::fs::create_dir_all(parent)
-            .map_err(|err| UntarError::IO("create_dir_all parent", err))?;
+/// Replaces the symlinks that could not be created by copies of their targets, or
+/// fails with the error of the first one whose target cannot be copied.
+fn copy_link_targets(copies: Vec<LinkCopy>) -> Result<(), ExtractError> {
+    for LinkCopy { path, target, err } in copies {
+        std::fs::copy(&target, &path).map_err(|_| ExtractError::IO("symlink", err))?;
+    }
+    Ok(())
+}
 
-        let mut bytes = vec![];
-        entry
-            .read_to_end(&mut bytes)
-            .map_err(|err| UntarError::IO("read_to_end", err))?;
-        std::fs::write(dest, bytes).map_err(|err| UntarError::IO("write", err))?;
+/// Creates `dest` and returns its canonical path, which extracted paths are checked
+/// against.
+fn canonical_dest(dest: &Path) -> Result<PathBuf, ExtractError> {
+    std::fs::create_dir_all(dest).map_err(|err| ExtractError::IO("create_dir_all dest", err))?;
+    dest.canonicalize()
+        .map_err(|err| ExtractError::IO("canonicalize dest", err))
+}
 
-        t.set_percentage(i as f64 / len);
+fn set_mode(path: &Path, mode: Option<u32>) -> Result<(), ExtractError> {
+    #[cfg(unix)]
+    if let Some(mode) = mode {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode & 0o777))
+            .map_err(|err| ExtractError::IO("set_permissions", err))?;
     }
+    #[cfg(not(unix))]
+    let _ = (path, mode);
+    Ok(())
+}
 
+/// Sets the modes and mtimes of extracted folders, deepest first, once their content
+/// is extracted.
+fn set_dirs_metadata(dirs: &[(PathBuf, Option<u32>, Option<u64>)]) -> Result<(), ExtractError> {
+    for (dir, mode, mtime) in dirs.iter().rev() {
+        set_mode(dir, *mode)?;
+        if let Some(mtime) = mtime {
+            let mtime = filetime::FileTime::from_unix_time(*mtime as i64, 0);
+            filetime::set_file_mtime(dir, mtime)
+                .map_err(|err| ExtractError::IO("set_file_mtime", err))?;
+        }
+    }
     Ok(())
 }
 
+/// Extracts the entries of a zip archive into `dest`, with the same checks as [`untar`].
+///
+/// Symlinks and permission modes are preserved.
+pub(crate) fn unzip(
+    r: impl Read + std::io::Seek,
+    dest: impl AsRef<Path>,
+) -> Result<(), ExtractError> {
+    let dest = canonical_dest(dest.as_ref())?;
+
+    let mut dirs = vec![];
+    let mut link_copies = vec![];
+
+    let mut f = zip::ZipArchive::new(r).map_err(ExtractError::Zip)?;
+    for i in 0..f.len() {
+        let mut entry = f.by_index(i).map_err(ExtractError::Zip)?;
+
+        let path = PathBuf::from(entry.name().map_err(ExtractError::Zip)?.as_ref());
+        let rel_path =
+            strip_first_component(&path).ok_or_else(|| ExtractError::OutsideDest(path.clone()))?;
+        if rel_path.as_os_str().is_empty() {
+            continue;
+        }
+
+        let mode = entry.unix_mode();
+        if entry.is_dir() {
+            let dir = create_dir_inside(&dest, &rel_path)?;
+            dirs.push((dir, mode, None));
+            continue;
+        }
+
+        let file_name = rel_path.file_name().ok_or(ExtractError::InvalidDest)?;
+        let parent = create_dir_inside(&dest, rel_path.parent().unwrap_or(Path::new("")))?;
+        let dest_path = parent.join(file_name);
+        let _ = std::fs::remove_file(&dest_path);
+
+        if entry.is_symlink() {
+            // The content of a symlink entry is its target
+            let mut target = String::new();
+            entry
+                .read_to_string(&mut target)
+                .map_err(|err| ExtractError::IO("read_to_string", err))?;
+            if !symlink_inside(&dest, &parent, Path::new(&target)) {
+                return Err(ExtractError::OutsideDest(path));
+            }
+            extract_symlink(Path::new(&target), &parent, &dest_path, &mut link_copies)?;
+        } else {
+            let mut f =
+                std::fs::File::create(&dest_path).map_err(|err| ExtractError::IO("create", err))?;
+            std::io::copy(&mut entry, &mut f).map_err(|err| ExtractError::IO("copy", err))?;
+            set_mode(&dest_path, mode)?;
+        }
+    }
+
+    copy_link_targets(link_copies)?;
+    set_dirs_metadata(&dirs)
+}
+
 #[derive(Error, Debug)]
 pub(crate) enum DownloadDecompressError {
-    #[error("download: {0}")]
-    Download(DownloadError),
-    #[error("unxz: {0}")]
-    Unxz(UnxzError),
+    #[error("{0}")]
+    IO(std::io::Error),
     #[error("ungz: {0}")]
     Ungz(std::io::Error),
-    #[error("untar: {0}")]
-    Untar(UntarError),
+    #[error("unzstd: {0}")]
+    Unzstd(std::io::Error),
+    #[error("{0}")]
+    Extract(ExtractError),
+    #[error("{0} is not a supported archive")]
+    UnsupportedArchive(String),
+    #[error("extraction failed: {0}")]
+    Join(tokio::task::JoinError),
+}
+
+/// Formats of the archives that can be extracted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ArchiveFormat {
+    Tar,
+    TarXz,
+    TarGz,
+    TarZst,
+    TarBz2,
+    Zip,
 }
 
-pub(crate) async fn download_unxz_untar(
+/// Where the magic of uncompressed tar archives is
+const USTAR_MAGIC_OFFSET: usize = 257;
+
+impl ArchiveFormat {
+    /// Detects the format from the first bytes of an archive, whatever its name is.
+    fn detect(magic: &[u8]) -> Option<ArchiveFormat> {
+        const MAGICS: &[(&[u8], ArchiveFormat)] = &[
+            (b"\xFD7zXZ\x00", ArchiveFormat::TarXz),
+            (b"\x1F\x8B", ArchiveFormat::TarGz),
+            (b"\x28\xB5\x2F\xFD", ArchiveFormat::TarZst),
+            (b"BZh", ArchiveFormat::TarBz2),
+            (b"PK\x03\x04", ArchiveFormat::Zip),
+            // An empty zip archive
+            (b"PK\x05\x06", ArchiveFormat::Zip),
+        ];
+
+        let format = MAGICS
+            .iter()
+            .find(|(bytes, _)| magic.starts_with(bytes))
+            .map(|(_, format)| *format);
+        let is_tar = magic
+            .get(USTAR_MAGIC_OFFSET..)
+            .is_some_and(|magic| magic.starts_with(b"ustar"));
+        format.or(is_tar.then_some(ArchiveFormat::Tar))
+    }
+}
+
+/// Extracts a local archive into `dest`. Tar archives, uncompressed or compressed
+/// with xz, gzip, zstd or bzip2, and zip archives are detected from their content.
+///
+/// The archive is decompressed while it is extracted, so it is never held in memory.
+/// Progress is how much of the compressed archive was read. Extraction runs aside, so
+/// it does not hold back downloads.
+pub(crate) async fn extract_archive(
     t: &TaskRef,
-    url: impl IntoUrl,
+    path: impl AsRef<Path>,
     dest: impl AsRef<Path>,
 ) -> Result<(), DownloadDecompressError> {
-    let llvm_tar_xz = download(t, url)
-        .await
-        .map_err(DownloadDecompressError::Download)?;
-    let llvm_tar = unxz(t, &llvm_tar_xz.path)
+    let t = t.clone();
+    let path = path.as_ref().to_path_buf();
+    let dest = dest.as_ref().to_path_buf();
+    tokio::task::spawn_blocking(move || extract_archive_blocking(&t, &path, &dest))
         .await
-        .map_err(DownloadDecompressError::Unxz)?;
-    untar_from_vec(t, llvm_tar, dest).map_err(DownloadDecompressError::Untar)?;
+        .map_err(DownloadDecompressError::Join)?
+}
 
-    Ok(())
+fn extract_archive_blocking(
+    t: &TaskRef,
+    path: &Path,
+    dest: &Path,
+) -> Result<(), DownloadDecompressError> {
+    use std::io::Seek;
+
+    let mut f = std::fs::File::open(path).map_err(DownloadDecompressError::IO)?;
+    let mut magic = vec![];
+    (&mut f)
+        .take(USTAR_MAGIC_OFFSET as u64 + 5)
+        .read_to_end(&mut magic)
+        .map_err(DownloadDecompressError::IO)?;
+    f.rewind().map_err(DownloadDecompressError::IO)?;
+
+    let Some(format) = ArchiveFormat::detect(&magic) else {
+        return Err(DownloadDecompressError::UnsupportedArchive(
+            path.display().to_string(),
+        ));
+    };
+
+    t.set_subtask("extracting");
+    // Decoders read a few bytes at a time
+    let f =
+        std::io::BufReader::new(ProgressReader::new(t, f).map_err(DownloadDecompressError::IO)?);
+    match format {
+        ArchiveFormat::Tar => untar(f, dest),
+        ArchiveFormat::Zip => unzip(f, dest),
+        ArchiveFormat::TarXz => untar(xz2::read::XzDecoder::new(f), dest),
+        ArchiveFormat::TarGz => {
+            let f = libflate::gzip::Decoder::new(f).map_err(DownloadDecompressError::Ungz)?;
+            untar(f, dest)
+        }
+        ArchiveFormat::TarZst => {
+            let f = zstd::Decoder::new(f).map_err(DownloadDecompressError::Unzstd)?;
+            untar(f, dest)
+        }
+        ArchiveFormat::TarBz2 => untar(bzip2::read::MultiBzDecoder::new(f), dest),
+    }
+    .map_err(DownloadDecompressError::Extract)
+}
+
+/// Where an archive to extract comes from.
+pub(crate) enum Archive<'a> {
+    /// Downloaded from the first of these urls that has it, see [`download`]
+    Download(&'a [String]),
+    /// In the cache already, verified when it was downloaded or made locally
+    Cached(PathBuf),
+    /// Given by the user, who keeps it
+    Local(&'a Path),
 }
 
-pub(crate) async fn download_ungz_untar(
+/// Downloads an archive when needed, verifies it and extracts it into `dest`, in place
+/// of what was there. Archives in the cache are removed once extracted.
+///
+/// Downloads and local archives are checked against `sha256`, when given, then by
+/// `verify`, before anything is extracted. Cached archives were checked already.
+pub(crate) async fn download_and_extract(
     t: &TaskRef,
-    url: impl IntoUrl,
-    dest: impl AsRef<Path>,
-) -> Result<PathBuf, DownloadDecompressError> {
-    let llvm_tar_gz = download(t, url)
-        .await
-        .map_err(DownloadDecompressError::Download)?;
-    let llvm_tar_gz_file_path = llvm_tar_gz.path.to_path_buf();
-    let llvm_tar = ungz(t, &llvm_tar_gz.path)
-        .await
-        .map_err(DownloadDecompressError::Ungz)?;
-    untar_from_vec(t, llvm_tar, dest).map_err(DownloadDecompressError::Untar)?;
+    archive: Archive<'_>,
+    sha256: Option<&str>,
+    verify: impl AsyncFnOnce(&Path) -> Result<(), Report>,
+    dest: &Path,
+) -> Result<(), Report> {
+    let (path, name, keep) = match archive {
+        Archive::Download(urls) => {
+            let name = urls
+                .last()
+                .and_then(|url| url.rsplit('/').next())
+                .unwrap_or_default()
+                .to_string();
+            let downloaded = download(t, urls, sha256, &cache_root()?)
+                .await
+                .wrap_err_with(|| format!("Downloading {name}"))?;
+            verify(&downloaded.path).await?;
+            (downloaded.path, name, false)
+        }
+        Archive::Cached(path) => {
+            let name = path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string();
+            (path, name, false)
+        }
+        Archive::Local(path) => {
+            let name = path.display().to_string();
+            if let Some(sha256) = sha256 {
+                verify_sha256(t, path, sha256).wrap_err_with(|| format!("Verifying {name}"))?;
+            }
+            verify(path).await?;
+            (path.to_path_buf(), name, true)
+        }
+    };
 
-    Ok(llvm_tar_gz_file_path)
+    let _ = std::fs::remove_dir_all(dest);
+    extract_archive(t, &path, dest)
+        .await
+        .wrap_err_with(|| format!("Processing {name}"))?;
+    if !keep {
+        t.set_subtask("Cleaning downloaded files...");
+        let _ = std::fs::remove_file(path);
+    }
+    Ok(())
 }
 
 // parses strings like: "[179/3416]"
@@ -326,20 +1335,345 @@ fn is_progress(line: &str) -> nom::IResult<&str, (usize, usize)> {
     Ok((line, (current, total)))
 }
 
-#[derive(Error, Debug)]
-pub(crate) enum SpawnError {
-    #[error("command not found")]
-    CommandNotFound,
-    #[error("{0}")]
-    IO(std::io::Error),
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct Version {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
 }
 
-pub(crate) fn spawn_cmake<I, S>(t: &TaskRef, args: I) -> Result<(), SpawnError>
-where
-    I: IntoIterator<Item = S>,
-    S: AsRef<std::ffi::OsStr>,
-{
-    let exe = search_cmake().ok_or(SpawnError::CommandNotFound)?;
+impl std::fmt::Display for Version {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+fn number(input: &str) -> nom::IResult<&str, u32> {
+    nom::combinator::map_opt(nom::character::complete::digit1, |s: &str| {
+        s.parse::<u32>().ok()
+    })(input)
+}
+
+// parses strings like: "17.0.6" or "llvmorg-17.0.6"
+fn parse_version(input: &str) -> nom::IResult<&str, Version> {
+    let (input, _) = nom::combinator::opt(nom::bytes::complete::tag("llvmorg-"))(input)?;
+    let (input, major) = number(input)?;
+    let (input, _) = nom::bytes::complete::tag(".")(input)?;
+    let (input, minor) = number(input)?;
+    let (input, _) = nom::bytes::complete::tag(".")(input)?;
+    let (input, patch) = number(input)?;
+
+    Ok((
+        input,
+        Version {
+            major,
+            minor,
+            patch,
+        },
+    ))
+}
+
+/// A version where trailing components may be omitted, like "17" or "17.0".
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct PartialVersion {
+    pub major: u32,
+    pub minor: Option<u32>,
+    pub patch: Option<u32>,
+}
+
+impl PartialVersion {
+    /// Smallest version matched by this partial version.
+    fn lower(&self) -> Version {
+        Version {
+            major: self.major,
+            minor: self.minor.unwrap_or(0),
+            patch: self.patch.unwrap_or(0),
+        }
+    }
+
+    /// Smallest version greater than every version matched by this partial version.
+    fn upper(&self) -> Version {
+        match (self.minor, self.patch) {
+            (Some(minor), Some(patch)) => Version {
+                major: self.major,
+                minor,
+                patch: patch + 1,
+            },
+            (Some(minor), None) => Version {
+                major: self.major,
+                minor: minor + 1,
+                patch: 0,
+            },
+            _ => Version {
+                major: self.major + 1,
+                minor: 0,
+                patch: 0,
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for PartialVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.major)?;
+        if let Some(minor) = self.minor {
+            write!(f, ".{minor}")?;
+        }
+        if let Some(patch) = self.patch {
+            write!(f, ".{patch}")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Comparison {
+    Eq,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+}
+
+/// What the user asked for, resolved against a release index.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum VersionSpec {
+    /// "latest"
+    Latest,
+    /// "17", "17.0" or "17.0.6"
+    Partial(PartialVersion),
+    /// "^18": any release with the same major
+    Caret(PartialVersion),
+    /// "~17.0": any release with the same major and minor
+    Tilde(PartialVersion),
+    /// ">=17, <19"
+    Range(Vec<(Comparison, PartialVersion)>),
+}
+
+impl VersionSpec {
+    /// The concrete version, when the spec names one and no index is needed.
+    pub fn exact(&self) -> Option<Version> {
+        match self {
+            VersionSpec::Partial(PartialVersion {
+                major,
+                minor: Some(minor),
+                patch: Some(patch),
+            }) => Some(Version {
+                major: *major,
+                minor: *minor,
+                patch: *patch,
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn matches(&self, v: &Version) -> bool {
+        match self {
+            VersionSpec::Latest => true,
+            VersionSpec::Partial(p) => p.lower() <= *v && *v < p.upper(),
+            VersionSpec::Caret(p) => {
+                p.lower() <= *v
+                    && *v
+                        < PartialVersion {
+                            major: p.major,
+                            minor: None,
+                            patch: None,
+                        }
+                        .upper()
+            }
+            VersionSpec::Tilde(p) => {
+                p.lower() <= *v
+                    && *v
+                        < PartialVersion {
+                            major: p.major,
+                            minor: p.minor,
+                            patch: None,
+                        }
+                        .upper()
+            }
+            VersionSpec::Range(comparisons) => comparisons.iter().all(|(op, p)| match op {
+                Comparison::Eq => p.lower() <= *v && *v < p.upper(),
+                Comparison::Gt => *v >= p.upper(),
+                Comparison::Ge => *v >= p.lower(),
+                Comparison::Lt => *v < p.lower(),
+                Comparison::Le => *v < p.upper(),
+            }),
+        }
+    }
+
+    /// Newest version of the index matching this spec.
+    pub fn resolve<'a>(&self, index: impl IntoIterator<Item = &'a Version>) -> Option<Version> {
+        index.into_iter().filter(|v| self.matches(v)).max().copied()
+    }
+}
+
+// parses strings like: "17", "17.0" or "17.0.6"
+fn parse_partial_version(input: &str) -> nom::IResult<&str, PartialVersion> {
+    let dot_number = || {
+        nom::combinator::opt(nom::sequence::preceded(
+            nom::bytes::complete::tag("."),
+            number,
+        ))
+    };
+
+    let (input, _) = nom::combinator::opt(nom::bytes::complete::tag("llvmorg-"))(input)?;
+    let (input, major) = number(input)?;
+    let (input, minor) = dot_number()(input)?;
+    let (input, patch) = match minor {
+        Some(_) => dot_number()(input)?,
+        None => (input, None),
+    };
+
+    Ok((
+        input,
+        PartialVersion {
+            major,
+            minor,
+            patch,
+        },
+    ))
+}
+
+// parses strings like: ">=17.0.4"
+fn parse_comparison(input: &str) -> nom::IResult<&str, (Comparison, PartialVersion)> {
+    use nom::{branch::alt, bytes::complete::tag, combinator::value};
+
+    let (input, op) = alt((
+        value(Comparison::Ge, tag(">=")),
+        value(Comparison::Le, tag("<=")),
+        value(Comparison::Gt, tag(">")),
+        value(Comparison::Lt, tag("<")),
+        value(Comparison::Eq, tag("=")),
+    ))(input)?;
+    let (input, _) = nom::character::complete::space0(input)?;
+    let (input, version) = parse_partial_version(input)?;
+
+    Ok((input, (op, version)))
+}
+
+// parses strings like: "latest", "17", "17.0", "^18", "~17.0" or ">=17, <19"
+fn parse_version_spec(input: &str) -> nom::IResult<&str, VersionSpec> {
+    use nom::{
+        branch::alt,
+        bytes::complete::{tag, tag_no_case},
+        character::complete::{char, space0, space1},
+        combinator::{map, value},
+        multi::separated_list1,
+        sequence::{delimited, preceded},
+    };
+
+    alt((
+        value(VersionSpec::Latest, tag_no_case("latest")),
+        map(
+            preceded(tag("^"), parse_partial_version),
+            VersionSpec::Caret,
+        ),
+        map(
+            preceded(tag("~"), parse_partial_version),
+            VersionSpec::Tilde,
+        ),
+        map(
+            separated_list1(
+                alt((delimited(space0, tag(","), space0), space1)),
+                parse_comparison,
+            ),
+            VersionSpec::Range,
+        ),
+        map(parse_partial_version, VersionSpec::Partial),
+        // "*" behaves like "latest"
+        value(VersionSpec::Latest, char('*')),
+    ))(input)
+}
+
+impl std::str::FromStr for VersionSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match nom::combinator::all_consuming(parse_version_spec)(s.trim()) {
+            Ok((_, spec)) => Ok(spec),
+            Err(_) => Err(format!("invalid version specifier: {s}")),
+        }
+    }
+}
+
+impl std::str::FromStr for Version {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match nom::combinator::all_consuming(parse_version)(s.trim()) {
+            Ok((_, version)) => Ok(version),
+            Err(_) => Err(format!("invalid version: {s}")),
+        }
+    }
+}
+
+// parses strings like: "set(LLVM_VERSION_MAJOR 17)"
+fn parse_cmake_set(line: &str) -> nom::IResult<&str, (&str, &str)> {
+    use nom::{
+        bytes::complete::{is_not, tag_no_case},
+        character::complete::{char, multispace0, multispace1},
+    };
+
+    let (line, _) = multispace0(line)?;
+    let (line, _) = tag_no_case("set")(line)?;
+    let (line, _) = multispace0(line)?;
+    let (line, _) = char('(')(line)?;
+    let (line, _) = multispace0(line)?;
+    let (line, name) = is_not(" \t\r\n)")(line)?;
+    let (line, _) = multispace1(line)?;
+    let (line, value) = is_not(" \t\r\n)")(line)?;
+    let (line, _) = multispace0(line)?;
+    let (line, _) = char(')')(line)?;
+
+    Ok((line, (name, value)))
+}
+
+// parses strings like: "MemTotal:       32594060 kB"
+fn parse_meminfo(line: &str) -> nom::IResult<&str, (&str, u64)> {
+    use nom::{
+        bytes::complete::{tag, take_until},
+        character::complete::space0,
+        combinator::opt,
+    };
+
+    let (line, name) = take_until(":")(line)?;
+    let (line, _) = tag(":")(line)?;
+    let (line, _) = space0(line)?;
+    let (line, value) = nom::combinator::map_opt(nom::character::complete::digit1, |s: &str| {
+        s.parse::<u64>().ok()
+    })(line)?;
+    let (line, _) = space0(line)?;
+    let (line, unit) = opt(tag("kB"))(line)?;
+
+    let value = if unit.is_some() { value * 1024 } else { value };
+    Ok((line, (name, value)))
+}
+
+/// Total physical memory in bytes, when the OS exposes `/proc/meminfo`.
+pub(crate) fn total_memory() -> Option<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    meminfo.lines().find_map(|line| match parse_meminfo(line) {
+        Ok((_, ("MemTotal", bytes))) => Some(bytes),
+        _ => None,
+    })
+}
+
+#[derive(Error, Debug)]
+pub(crate) enum SpawnError {
+    #[error("command not found")]
+    CommandNotFound,
+    #[error("{0}")]
+    IO(std::io::Error),
+    #[error("{0}")]
+    Failed(String),
+}
+
+pub(crate) fn spawn_cmake<I, S>(t: &TaskRef, args: I) -> Result<(), SpawnError>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<std::ffi::OsStr>,
+{
+    let exe = search_cmake().ok_or(SpawnError::CommandNotFound)?;
 
     let mut process = std::process::Command::new(&exe)
         .args(args)
@@ -348,11 +1682,23 @@ where
         .spawn()
         .map_err(SpawnError::IO)?;
 
+    // Read aside, so a full stderr pipe never blocks cmake
+    let stderr = process.stderr.take().map(|stderr| {
+        std::thread::spawn(move || {
+            let mut lines = std::io::BufReader::new(stderr)
+                .lines()
+                .map_while(Result::ok)
+                .collect::<Vec<_>>();
+            let tail = lines.len().saturating_sub(20);
+            lines.split_off(tail)
+        })
+    });
+
     if let Some(stdout) = process.stdout.take() {
         let lines = std::io::BufReader::new(stdout);
         let mut last_percentage = 0.0;
 
-        for line in lines.lines().flatten() {
+        for line in lines.lines().map_while(Result::ok) {
             if let Ok((_, (current, total))) = is_progress(&line) {
                 last_percentage = current as f64 / total as f64;
             }
@@ -360,7 +1706,18 @@ where
             t.set_subtask_with_percentage(&line, last_percentage);
         }
     }
-    process.wait().map_err(SpawnError::IO)?;
+    let status = process.wait().map_err(SpawnError::IO)?;
+    let stderr = stderr
+        .and_then(|stderr| stderr.join().ok())
+        .unwrap_or_default();
+    if !status.success() {
+        let mut msg = format!("cmake {status}");
+        for line in stderr {
+            msg.push('\n');
+            msg.push_str(&line);
+        }
+        return Err(SpawnError::Failed(msg));
+    }
 
     t.set_subtask_with_percentage("", 1.0);
 
@@ -372,6 +1729,165 @@ pub struct Shell {
     pub env_vars: HashMap<String, String>,
 }
 
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Profile {
+    #[default]
+    Release,
+    Debug,
+    RelWithDebInfo,
+}
+
+impl Profile {
+    pub fn cmake_build_type(&self) -> &'static str {
+        match self {
+            Profile::Release => "Release",
+            Profile::Debug => "Debug",
+            Profile::RelWithDebInfo => "RelWithDebInfo",
+        }
+    }
+
+    /// Rough peak memory of one linker process, used to limit parallel link jobs.
+    pub fn link_job_memory(&self) -> u64 {
+        const GIB: u64 = 1024 * 1024 * 1024;
+        match self {
+            Profile::Release => 4 * GIB,
+            Profile::Debug | Profile::RelWithDebInfo => 12 * GIB,
+        }
+    }
+}
+
+impl std::fmt::Display for Profile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Profile::Release => write!(f, "release"),
+            Profile::Debug => write!(f, "debug"),
+            Profile::RelWithDebInfo => write!(f, "relwithdebinfo"),
+        }
+    }
+}
+
+impl std::str::FromStr for Profile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "release" => Ok(Profile::Release),
+            "debug" => Ok(Profile::Debug),
+            "relwithdebinfo" => Ok(Profile::RelWithDebInfo),
+            _ => Err(format!(
+                "invalid profile: {s}. Options: release, debug, relwithdebinfo"
+            )),
+        }
+    }
+}
+
+/// Appended to the install folder, so variants of the same version live side by side.
+pub(crate) fn variant_suffix(profile: Profile, assertions: bool) -> String {
+    let mut suffix = String::new();
+    if profile != Profile::Release {
+        suffix.push_str(&format!("-{profile}"));
+    }
+    if assertions {
+        suffix.push_str("-asserts");
+    }
+    suffix
+}
+
+/// How an install was built, stored next to it so it can be reproduced.
+#[derive(Default, Serialize, Deserialize)]
+pub struct InstallMetadata {
+    pub version: String,
+    #[serde(default)]
+    pub profile: Profile,
+    #[serde(default)]
+    pub assertions: bool,
+    #[serde(default)]
+    pub cmake_args: Vec<String>,
+    #[serde(default)]
+    pub projects: Vec<String>,
+    #[serde(default)]
+    pub runtimes: Vec<String>,
+    /// LLVM_TARGETS_TO_BUILD, or all targets when `None`
+    #[serde(default)]
+    pub targets: Option<Vec<String>>,
+    #[serde(default)]
+    pub experimental_targets: Vec<String>,
+    /// Release asset of the binaries, when installed with `--prebuilt`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prebuilt: Option<String>,
+}
+
+impl InstallMetadata {
+    /// Like "release" or "debug+asserts"
+    pub fn variant(&self) -> String {
+        if self.assertions {
+            format!("{}+asserts", self.profile)
+        } else {
+            self.profile.to_string()
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub(crate) enum InstallMetadataError {
+    #[error("{0}")]
+    FileSystem(FileSystemError),
+    #[error("{0}")]
+    IO(std::io::Error),
+    #[error("{0}")]
+    Serialization(serde_json::Error),
+}
+
+const INSTALL_METADATA_FILE: &str = "llvmgr.json";
+
+pub(crate) fn read_install_metadata(
+    prefix: impl AsRef<Path>,
+) -> Result<Option<InstallMetadata>, InstallMetadataError> {
+    let path = prefix.as_ref().join(INSTALL_METADATA_FILE);
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let metadata = std::fs::read_to_string(path).map_err(InstallMetadataError::IO)?;
+    serde_json::from_str(&metadata)
+        .map(Some)
+        .map_err(InstallMetadataError::Serialization)
+}
+
+/// Every install inside the cache folder, sorted by folder name.
+///
+/// Installs whose metadata cannot be read are skipped with a warning, so one of them
+/// does not hide the others.
+pub(crate) fn list_installs() -> Result<Vec<(PathBuf, InstallMetadata)>, InstallMetadataError> {
+    let root = cache_root().map_err(InstallMetadataError::FileSystem)?;
+
+    let mut installs = vec![];
+    for entry in std::fs::read_dir(root).map_err(InstallMetadataError::IO)? {
+        let path = entry.map_err(InstallMetadataError::IO)?.path();
+        match read_install_metadata(&path) {
+            Ok(Some(metadata)) => installs.push((path, metadata)),
+            Ok(None) => {}
+            Err(err) => eprintln!(
+                "warning: skipping {}: {err}",
+                path.join(INSTALL_METADATA_FILE).display()
+            ),
+        }
+    }
+    installs.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    Ok(installs)
+}
+
+pub(crate) fn write_install_metadata(
+    prefix: impl AsRef<Path>,
+    metadata: &InstallMetadata,
+) -> Result<(), InstallMetadataError> {
+    let path = prefix.as_ref().join(INSTALL_METADATA_FILE);
+    let metadata = serde_json::to_string_pretty(metadata).expect("this should not fail");
+    std::fs::write(path, metadata).map_err(InstallMetadataError::IO)
+}
+
 #[derive(Error, Debug)]
 pub(crate) enum ReadShellError {
     #[error("{0}")]
@@ -402,20 +1918,156 @@ pub(crate) fn write_shell(shell: &Shell) -> Result<(), ReadShellError> {
     std::fs::write(shell_path, shell).map_err(ReadShellError::IO)
 }
 
-pub(crate) fn move_dir(
-    src: impl AsRef<Path>,
-    dest: impl AsRef<Path>,
-) -> Result<(), FileSystemError> {
-    let options = CopyOptions::default().overwrite(true);
-    let _ = fs_extra::move_items(&[src], dest, &options).map_err(FileSystemError::CannotMove);
+/// Name of the LLVM backend able to generate code for the host.
+pub(crate) fn host_llvm_target() -> &'static str {
+    match std::env::consts::ARCH {
+        "x86" | "x86_64" => "X86",
+        "aarch64" => "AArch64",
+        "arm" => "ARM",
+        "riscv32" | "riscv64" => "RISCV",
+        "powerpc" | "powerpc64" => "PowerPC",
+        "mips" | "mips64" => "Mips",
+        "s390x" => "SystemZ",
+        "sparc64" => "Sparc",
+        "loongarch64" => "LoongArch",
+        "wasm32" | "wasm64" => "WebAssembly",
+        _ => "host",
+    }
+}
 
-    Ok(())
+/// Host triple as used by the names of LLVM prebuilt release assets.
+pub(crate) fn host_triple() -> String {
+    let os = match std::env::consts::OS {
+        "linux" => "linux-gnu",
+        "macos" => "apple-darwin",
+        "windows" => "pc-windows-msvc",
+        os => os,
+    };
+    format!("{}-{os}", std::env::consts::ARCH)
 }
 
-pub(crate) fn remove_dir(dir: impl AsRef<Path>) -> Result<(), FileSystemError> {
-    let _ = fs_extra::remove_items(&[dir]).map_err(FileSystemError::CannotRemove);
+/// Runs git inside `repo` and returns its stdout.
+pub(crate) fn git<I, S>(repo: impl AsRef<Path>, args: I) -> Result<String, SpawnError>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<std::ffi::OsStr>,
+{
+    let exe = which::which("git").map_err(|_| SpawnError::CommandNotFound)?;
 
-    Ok(())
+    let output = std::process::Command::new(exe)
+        .arg("-C")
+        .arg(repo.as_ref())
+        .args(args)
+        .output()
+        .map_err(SpawnError::IO)?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(SpawnError::Failed(stderr.trim().to_string()));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CompilerCache {
+    #[default]
+    Auto,
+    Off,
+    Ccache,
+    Sccache,
+}
+
+impl std::fmt::Display for CompilerCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CompilerCache::Auto => write!(f, "auto"),
+            CompilerCache::Off => write!(f, "off"),
+            CompilerCache::Ccache => write!(f, "ccache"),
+            CompilerCache::Sccache => write!(f, "sccache"),
+        }
+    }
+}
+
+impl std::str::FromStr for CompilerCache {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "auto" => Ok(CompilerCache::Auto),
+            "off" => Ok(CompilerCache::Off),
+            "ccache" => Ok(CompilerCache::Ccache),
+            "sccache" => Ok(CompilerCache::Sccache),
+            _ => Err(format!(
+                "invalid compiler cache: {s}. Options: auto, off, ccache, sccache"
+            )),
+        }
+    }
+}
+
+/// Finds the compiler cache executable. `Auto` prefers ccache over sccache.
+pub(crate) fn search_compiler_cache(cache: CompilerCache) -> Option<(CompilerCache, PathBuf)> {
+    match cache {
+        CompilerCache::Off => None,
+        CompilerCache::Ccache => which::which("ccache")
+            .ok()
+            .map(|path| (CompilerCache::Ccache, path)),
+        CompilerCache::Sccache => which::which("sccache")
+            .ok()
+            .map(|path| (CompilerCache::Sccache, path)),
+        CompilerCache::Auto => search_compiler_cache(CompilerCache::Ccache)
+            .or_else(|| search_compiler_cache(CompilerCache::Sccache)),
+    }
+}
+
+// parses strings like: "  Hits:   123 / 456 (26.97 %)" or "Cache hits    123"
+fn parse_cache_stat(line: &str) -> nom::IResult<&str, (&str, u64)> {
+    use nom::{bytes::complete::take_till1, character::complete::digit1};
+
+    let (line, label) = take_till1(|c: char| c.is_ascii_digit())(line)?;
+    let (line, value) = nom::combinator::map_opt(digit1, |s: &str| s.parse::<u64>().ok())(line)?;
+
+    let label = label.trim().trim_end_matches(':').trim();
+    Ok((line, (label, value)))
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// Cumulative hits and misses of ccache or sccache.
+pub(crate) fn compiler_cache_stats(exe: &Path) -> Option<CacheStats> {
+    let output = std::process::Command::new(exe)
+        .arg("--show-stats")
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    let mut hits = None;
+    let mut misses = None;
+    let mut ccache3_hits = 0;
+    for line in output.stdout.lines().map_while(Result::ok) {
+        let Ok((_, (label, value))) = parse_cache_stat(&line) else {
+            continue;
+        };
+        match label {
+            // ccache 4 and sccache. Only the first occurrence is the total.
+            "Hits" | "Cache hits" if hits.is_none() => hits = Some(value),
+            "Misses" | "Cache misses" if misses.is_none() => misses = Some(value),
+            // ccache 3
+            "cache hit (direct)" | "cache hit (preprocessed)" => ccache3_hits += value,
+            "cache miss" if misses.is_none() => misses = Some(value),
+            _ => {}
+        }
+    }
+
+    Some(CacheStats {
+        hits: hits.unwrap_or(ccache3_hits),
+        misses: misses?,
+    })
 }
 
 pub(crate) fn search_cmake() -> Option<PathBuf> {
@@ -459,7 +2111,7 @@ pub(crate) fn get_cmake_default_generator(cmake: PathBuf) -> Result<String, Repo
         .output()
         .map_err(SpawnError::IO)?;
 
-    for line in output.stdout.lines().flatten() {
+    for line in output.stdout.lines().map_while(Result::ok) {
         if let Some(generator) = line.strip_prefix("* ").and_then(|s| s.split('=').next()) {
             return Ok(generator.trim().into());
         }
@@ -478,3 +2130,888 @@ pub(crate) fn get_cmake_default_generator(cmake: PathBuf) -> Result<String, Repo
             .with_suggestion(|| "Install `Microsoft Visual Studio`")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        download, download_and_extract, extract_archive, fetch, parse_content_range,
+        test_server::{refused_url, Response, TestServer},
+        untar, unzip, verify_sha256, Archive, ArchiveFormat, ChunkProgress, Comparison,
+        DownloadError, ExtractError, Mirrors, PartialDownload, PartialVersion, Version,
+        VersionSpec,
+    };
+    use crate::{commands::http::http_client, tasks::Tasks};
+    use std::{
+        path::{Path, PathBuf},
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+    };
+
+    /// Entry of a crafted archive. Paths are written as they are, which `tar::Builder`
+    /// would refuse for `..` and absolute paths.
+    enum Entry<'a> {
+        File(&'a str, &'a str),
+        Symlink(&'a str, &'a str),
+        Hardlink(&'a str, &'a str),
+        /// A file of mode 0o755, modified at [`MTIME`]
+        Executable(&'a str, &'a str),
+    }
+
+    const MTIME: u64 = 1_700_000_000;
+
+    fn archive(entries: &[Entry]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(vec![]);
+        for entry in entries {
+            let (path, kind, link, contents) = match entry {
+                Entry::File(path, contents) | Entry::Executable(path, contents) => {
+                    (path, tar::EntryType::Regular, "", *contents)
+                }
+                Entry::Symlink(path, target) => (path, tar::EntryType::Symlink, *target, ""),
+                Entry::Hardlink(path, target) => (path, tar::EntryType::Link, *target, ""),
+            };
+            let (mode, mtime) = match entry {
+                Entry::Executable(..) => (0o755, MTIME),
+                _ => (0o644, 0),
+            };
+
+            let mut header = tar::Header::new_old();
+            header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+            header.as_old_mut().linkname[..link.len()].copy_from_slice(link.as_bytes());
+            header.set_entry_type(kind);
+            header.set_mode(mode);
+            header.set_mtime(mtime);
+            header.set_size(contents.len() as u64);
+            header.set_cksum();
+            builder.append(&header, contents.as_bytes()).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    /// An empty folder, with `dest` to extract into.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("llvmgr-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("dest")).unwrap();
+        dir
+    }
+
+    fn extract(dir: &Path, entries: &[Entry]) -> Result<(), ExtractError> {
+        untar(archive(entries).as_slice(), dir.join("dest"))
+    }
+
+    #[test]
+    fn extracts_links_inside_dest() {
+        let dir = test_dir("links");
+        extract(
+            &dir,
+            &[
+                Entry::File("root/bin/tool", "tool"),
+                Entry::Symlink("root/lib/tool", "../bin/tool"),
+                Entry::Hardlink("root/tool", "root/bin/tool"),
+            ],
+        )
+        .unwrap();
+
+        let dest = dir.join("dest");
+        assert_eq!(
+            std::fs::read_to_string(dest.join("lib/tool")).unwrap(),
+            "tool"
+        );
+        assert_eq!(std::fs::read_to_string(dest.join("tool")).unwrap(), "tool");
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn rejects_parent_dir() {
+        let dir = test_dir("parent");
+        let result = extract(&dir, &[Entry::File("root/../../evil", "evil")]);
+
+        assert!(matches!(result, Err(ExtractError::OutsideDest(_))));
+        assert!(!dir.join("evil").exists());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn rejects_absolute_path() {
+        let dir = test_dir("absolute");
+        let evil = dir.join("evil");
+        let result = extract(&dir, &[Entry::File(evil.to_str().unwrap(), "evil")]);
+
+        assert!(matches!(result, Err(ExtractError::OutsideDest(_))));
+        assert!(!evil.exists());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn rejects_symlink_outside_dest() {
+        let dir = test_dir("symlink");
+        let result = extract(&dir, &[Entry::Symlink("root/link", "../..")]);
+        assert!(matches!(result, Err(ExtractError::OutsideDest(_))));
+
+        let result = extract(&dir, &[Entry::Symlink("root/link", "/tmp")]);
+        assert!(matches!(result, Err(ExtractError::OutsideDest(_))));
+
+        let result = extract(&dir, &[Entry::Symlink("root/link", "bin/../..")]);
+        assert!(matches!(result, Err(ExtractError::OutsideDest(_))));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rejects_writing_through_symlink() {
+        let dir = test_dir("through");
+        // A symlink left in the destination, which the archive writes through
+        std::os::unix::fs::symlink(&dir, dir.join("dest/link")).unwrap();
+        let result = extract(&dir, &[Entry::File("root/link/evil", "evil")]);
+
+        assert!(matches!(result, Err(ExtractError::OutsideDest(_))));
+        assert!(!dir.join("evil").exists());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn extracts_modes_and_mtimes() {
+        let dir = test_dir("modes");
+        extract(&dir, &[Entry::Executable("root/bin/tool", "tool")]).unwrap();
+
+        let metadata = std::fs::metadata(dir.join("dest/bin/tool")).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(metadata.permissions().mode() & 0o777, 0o755);
+        }
+        assert_eq!(
+            metadata.modified().unwrap(),
+            std::time::UNIX_EPOCH + std::time::Duration::from_secs(MTIME)
+        );
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn rejects_hardlink_outside_dest() {
+        let dir = test_dir("hardlink");
+        std::fs::write(dir.join("secret"), "secret").unwrap();
+        let result = extract(&dir, &[Entry::Hardlink("root/link", "root/../secret")]);
+
+        assert!(matches!(result, Err(ExtractError::OutsideDest(_))));
+        assert!(!dir.join("dest/link").exists());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn rejects_zip_parent_dir() {
+        use std::io::Write;
+
+        let dir = test_dir("zip");
+        let mut zip = zip::ZipWriter::new(std::io::Cursor::new(vec![]));
+        let options = zip::write::SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Stored);
+        zip.start_file("root/../../evil", options).unwrap();
+        zip.write_all(b"evil").unwrap();
+        let zip = zip.finish().unwrap();
+        let result = unzip(zip, dir.join("dest"));
+
+        assert!(matches!(result, Err(ExtractError::OutsideDest(_))));
+        assert!(!dir.join("evil").exists());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn detects_archive_formats() {
+        let tar = tar::Header::new_gnu();
+        assert_eq!(
+            ArchiveFormat::detect(tar.as_bytes()),
+            Some(ArchiveFormat::Tar)
+        );
+        assert_eq!(
+            ArchiveFormat::detect(b"\xFD7zXZ\x00\x00"),
+            Some(ArchiveFormat::TarXz)
+        );
+        assert_eq!(
+            ArchiveFormat::detect(b"\x1F\x8B\x08"),
+            Some(ArchiveFormat::TarGz)
+        );
+        assert_eq!(
+            ArchiveFormat::detect(b"\x28\xB5\x2F\xFD"),
+            Some(ArchiveFormat::TarZst)
+        );
+        assert_eq!(ArchiveFormat::detect(b"BZh9"), Some(ArchiveFormat::TarBz2));
+        assert_eq!(
+            ArchiveFormat::detect(b"PK\x03\x04"),
+            Some(ArchiveFormat::Zip)
+        );
+        assert_eq!(ArchiveFormat::detect(b"<html>"), None);
+    }
+
+    /// Checks what was extracted from an archive of `root/bin/tool` and a `root/lib/tool`
+    /// symlink to it.
+    fn check_tool(dest: &Path) {
+        assert_eq!(
+            std::fs::read_to_string(dest.join("bin/tool")).unwrap(),
+            "tool"
+        );
+        assert_eq!(
+            std::fs::read_to_string(dest.join("lib/tool")).unwrap(),
+            "tool"
+        );
+        #[cfg(unix)]
+        assert!(dest.join("lib/tool").is_symlink());
+    }
+
+    #[tokio::test]
+    async fn extracts_compressed_tar_archives() {
+        use std::io::Write;
+
+        let dir = test_dir("compressed");
+        let tar = archive(&[
+            Entry::File("root/bin/tool", "tool"),
+            Entry::Symlink("root/lib/tool", "../bin/tool"),
+        ]);
+        let mut bz2 = bzip2::write::BzEncoder::new(vec![], bzip2::Compression::default());
+        bz2.write_all(&tar).unwrap();
+        let mut xz = xz2::write::XzEncoder::new(vec![], 6);
+        xz.write_all(&tar).unwrap();
+        let archives = [
+            ("zst", zstd::encode_all(tar.as_slice(), 0).unwrap()),
+            ("bz2", bz2.finish().unwrap()),
+            ("xz", xz.finish().unwrap()),
+        ];
+        let mut tasks = Tasks::hidden();
+        let t = tasks.new_task("test").unwrap();
+
+        // Formats are detected from the content, not the name
+        for (name, compressed) in archives {
+            let path = dir.join(name);
+            std::fs::write(&path, compressed).unwrap();
+            let dest = dir.join(format!("dest-{name}"));
+            extract_archive(&t, &path, &dest).await.unwrap();
+            check_tool(&dest);
+        }
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn extracts_zip_archives() {
+        use std::io::Write;
+
+        let dir = test_dir("unzip");
+        let mut zip = zip::ZipWriter::new(std::io::Cursor::new(vec![]));
+        let options = zip::write::SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Deflated);
+        zip.add_directory("root/bin/", options).unwrap();
+        zip.start_file("root/bin/tool", options.unix_permissions(0o755))
+            .unwrap();
+        zip.write_all(b"tool").unwrap();
+        zip.add_symlink("root/lib/tool", "../bin/tool", options)
+            .unwrap();
+        let path = dir.join("archive");
+        std::fs::write(&path, zip.finish().unwrap().into_inner()).unwrap();
+        let mut tasks = Tasks::hidden();
+        let t = tasks.new_task("test").unwrap();
+
+        let dest = dir.join("dest");
+        extract_archive(&t, &path, &dest).await.unwrap();
+        check_tool(&dest);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let metadata = std::fs::metadata(dest.join("bin/tool")).unwrap();
+            assert_eq!(metadata.permissions().mode() & 0o777, 0o755);
+        }
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn verifies_archives_before_extracting_them() {
+        let dir = test_dir("verify");
+        let tar = archive(&[Entry::File("root/bin/tool", "tool")]);
+        let path = dir.join("archive.tar.zst");
+        std::fs::write(&path, zstd::encode_all(tar.as_slice(), 0).unwrap()).unwrap();
+        let dest = dir.join("dest");
+        std::fs::write(dest.join("previous"), "previous").unwrap();
+        let mut tasks = Tasks::hidden();
+        let t = tasks.new_task("test").unwrap();
+
+        let result = download_and_extract(
+            &t,
+            Archive::Local(&path),
+            Some(&sha256(b"other contents")),
+            async |_: &Path| Ok(()),
+            &dest,
+        )
+        .await;
+        assert!(result.is_err());
+        let result = download_and_extract(
+            &t,
+            Archive::Local(&path),
+            None,
+            async |_: &Path| Err(color_eyre::eyre::eyre!("bad signature")),
+            &dest,
+        )
+        .await;
+        assert!(result.is_err());
+        assert!(dest.join("previous").exists());
+
+        let mut verified = vec![];
+        let digest = sha256(&std::fs::read(&path).unwrap());
+        download_and_extract(
+            &t,
+            Archive::Local(&path),
+            Some(&digest),
+            async |path: &Path| {
+                verified.push(path.to_path_buf());
+                Ok(())
+            },
+            &dest,
+        )
+        .await
+        .unwrap();
+        assert_eq!(verified, [path.as_path()]);
+        assert!(!dest.join("previous").exists());
+        assert!(dest.join("bin/tool").exists());
+        // Local archives are kept, cached ones are removed once extracted
+        assert!(path.exists());
+
+        download_and_extract(
+            &t,
+            Archive::Cached(path.clone()),
+            None,
+            async |_: &Path| unreachable!("cached archives were verified already"),
+            &dest,
+        )
+        .await
+        .unwrap();
+        assert!(dest.join("bin/tool").exists());
+        assert!(!path.exists());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    fn version(s: &str) -> Version {
+        s.parse().unwrap()
+    }
+
+    fn partial(major: u32, minor: Option<u32>, patch: Option<u32>) -> PartialVersion {
+        PartialVersion {
+            major,
+            minor,
+            patch,
+        }
+    }
+
+    /// Tags of the release index, with release candidates that are left out of it
+    fn index() -> Vec<Version> {
+        [
+            "llvmorg-15.0.7",
+            "llvmorg-16.0.0",
+            "llvmorg-16.0.6",
+            "llvmorg-17.0.1",
+            "llvmorg-17.0.6",
+            "llvmorg-18.1.0-rc3",
+            "llvmorg-18.1.8",
+            "llvmorg-19.1.0-rc1",
+        ]
+        .iter()
+        .filter_map(|tag| tag.parse().ok())
+        .collect()
+    }
+
+    #[test]
+    fn parses_versions() {
+        assert_eq!(
+            version("llvmorg-17.0.6"),
+            Version {
+                major: 17,
+                minor: 0,
+                patch: 6
+            }
+        );
+        assert!("17.0".parse::<Version>().is_err());
+        assert!("llvmorg-18.1.0-rc3".parse::<Version>().is_err());
+        assert_eq!(index().len(), 6);
+    }
+
+    #[test]
+    fn parses_version_specs() {
+        let spec = |s: &str| s.parse::<VersionSpec>().unwrap();
+        assert_eq!(spec("latest"), VersionSpec::Latest);
+        assert_eq!(spec("*"), VersionSpec::Latest);
+        assert_eq!(spec("17"), VersionSpec::Partial(partial(17, None, None)));
+        assert_eq!(
+            spec("17.0"),
+            VersionSpec::Partial(partial(17, Some(0), None))
+        );
+        assert_eq!(
+            spec(" 17.0.6 "),
+            VersionSpec::Partial(partial(17, Some(0), Some(6)))
+        );
+        assert_eq!(spec("^18"), VersionSpec::Caret(partial(18, None, None)));
+        assert_eq!(
+            spec("~17.0"),
+            VersionSpec::Tilde(partial(17, Some(0), None))
+        );
+        assert_eq!(
+            spec(">=17, <19"),
+            VersionSpec::Range(vec![
+                (Comparison::Ge, partial(17, None, None)),
+                (Comparison::Lt, partial(19, None, None)),
+            ])
+        );
+        assert_eq!(spec(">= 17 <19"), spec(">=17, <19"));
+
+        for invalid in ["", "17.", "^", "~x", ">=17,", "17.0.6-rc1", "newest"] {
+            assert!(invalid.parse::<VersionSpec>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn resolves_version_specs() {
+        let resolve = |s: &str| s.parse::<VersionSpec>().unwrap().resolve(&index());
+        assert_eq!(resolve("latest"), Some(version("18.1.8")));
+        assert_eq!(resolve("17"), Some(version("17.0.6")));
+        assert_eq!(resolve("16.0"), Some(version("16.0.6")));
+        assert_eq!(resolve("^16"), Some(version("16.0.6")));
+        assert_eq!(resolve("^17.0.2"), Some(version("17.0.6")));
+        assert_eq!(resolve("~17.0.1"), Some(version("17.0.6")));
+        assert_eq!(resolve(">=16, <18"), Some(version("17.0.6")));
+        assert_eq!(resolve(">16.0, <=17.0.1"), Some(version("17.0.1")));
+        assert_eq!(resolve("=15"), Some(version("15.0.7")));
+
+        // Release candidates are not in the index
+        assert_eq!(resolve("19"), None);
+        assert_eq!(resolve("^20"), None);
+        assert_eq!(resolve(">18, <18.1"), None);
+    }
+
+    #[test]
+    fn exact_versions_need_no_index() {
+        let spec = |s: &str| s.parse::<VersionSpec>().unwrap();
+        assert_eq!(spec("17.0.6").exact(), Some(version("17.0.6")));
+        assert_eq!(spec("17.0").exact(), None);
+        assert_eq!(spec("^17.0.6").exact(), None);
+    }
+
+    #[test]
+    fn parses_content_ranges() {
+        assert_eq!(
+            parse_content_range("bytes 100-199/200"),
+            Ok(("", (100, Some(200))))
+        );
+        assert_eq!(parse_content_range("bytes 0-0/*"), Ok(("", (0, None))));
+        assert!(parse_content_range("bytes */200").is_err());
+        assert!(parse_content_range("items 0-1/2").is_err());
+        assert!(parse_content_range("bytes 1-/2").is_err());
+    }
+
+    /// Contents of a download, large enough to be interrupted in the middle
+    fn contents(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    /// Leaves a partial download in `dir`, as an interrupted run would have.
+    fn write_partial(dir: &Path, part: &[u8], etag: &str, content_length: usize) {
+        std::fs::write(dir.join("file.tar.gz.part"), part).unwrap();
+        let partial = PartialDownload {
+            url: None,
+            etag: Some(etag.into()),
+            last_modified: None,
+            content_length: Some(content_length as u64),
+            chunks: vec![],
+        };
+        std::fs::write(
+            dir.join("file.tar.gz.part.json"),
+            serde_json::to_string(&partial).unwrap(),
+        )
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn resumes_interrupted_downloads() {
+        let dir = test_dir("resume");
+        let body = contents(256 * 1024);
+        let served = body.clone();
+        let server = TestServer::start(move |req| {
+            let res = Response::file(req, &served, "\"v1\"");
+            if req.header("range").is_none() {
+                res.cut(served.len() / 2)
+            } else {
+                res
+            }
+        })
+        .await;
+        let url = format!("{}/file.tar.gz", server.url);
+        let mut tasks = Tasks::hidden();
+        let t = tasks.new_task("test").unwrap();
+
+        assert!(fetch(&t, &url, &dir).await.is_err());
+        let part = std::fs::metadata(dir.join("file.tar.gz.part"))
+            .unwrap()
+            .len();
+        assert!(part > 0 && part < body.len() as u64);
+
+        let downloaded = fetch(&t, &url, &dir).await.unwrap();
+        assert_eq!(std::fs::read(downloaded.path).unwrap(), body);
+        assert!(!dir.join("file.tar.gz.part.json").exists());
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1].path, "/file.tar.gz");
+        assert_eq!(
+            requests[1].header("range"),
+            Some(format!("bytes={part}-").as_str())
+        );
+        assert_eq!(requests[1].header("if-range"), Some("\"v1\""));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn resumes_missing_chunks() {
+        let dir = test_dir("chunks");
+        let body = contents(16 * 1024 * 1024);
+        let served = body.clone();
+        let interrupt = Arc::new(AtomicBool::new(true));
+        let interrupted = interrupt.clone();
+        let server = TestServer::start(move |req| {
+            let res = Response::file(req, &served, "\"v1\"");
+            if req.header("range").is_some() && interrupted.load(Ordering::Relaxed) {
+                res.cut(3 * 1024 * 1024)
+            } else {
+                res
+            }
+        })
+        .await;
+        let url = format!("{}/file.tar.gz", server.url);
+        let mut tasks = Tasks::hidden();
+        let t = tasks.new_task("test").unwrap();
+
+        assert!(fetch(&t, &url, &dir).await.is_err());
+        assert!(dir.join("file.tar.gz.part.json").exists());
+        interrupt.store(false, Ordering::Relaxed);
+        let first = server.requests().len();
+        let downloaded = fetch(&t, &url, &dir).await.unwrap();
+        assert_eq!(std::fs::read(downloaded.path).unwrap(), body);
+        assert!(!dir.join("file.tar.gz.part.json").exists());
+
+        // The first chunk was read whole, the others are requested from where they stopped
+        let connections = http_client().unwrap().connections();
+        let chunk_length = body.len().div_ceil(connections);
+        let mut expected = (chunk_length..body.len())
+            .step_by(chunk_length)
+            .map(|start| {
+                let end = (start + chunk_length).min(body.len());
+                format!("bytes={}-{}", start + 3 * 1024 * 1024, end - 1)
+            })
+            .collect::<Vec<_>>();
+        let requests = server.requests();
+        assert!(requests[first..]
+            .iter()
+            .all(|req| req.header("if-range") == Some("\"v1\"")));
+        let mut ranges = requests[first..]
+            .iter()
+            .map(|req| req.header("range").unwrap().to_string())
+            .collect::<Vec<_>>();
+        ranges.sort();
+        expected.sort();
+        assert_eq!(ranges, expected);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn restarts_downloads_of_changed_files() {
+        let dir = test_dir("changed");
+        let body = contents(64 * 1024);
+        write_partial(&dir, b"old contents", "\"v1\"", body.len());
+        let served = body.clone();
+        let server = TestServer::start(move |req| Response::file(req, &served, "\"v2\"")).await;
+        let mut tasks = Tasks::hidden();
+        let t = tasks.new_task("test").unwrap();
+
+        let url = format!("{}/file.tar.gz", server.url);
+        let downloaded = fetch(&t, &url, &dir).await.unwrap();
+        assert_eq!(std::fs::read(downloaded.path).unwrap(), body);
+        assert_eq!(server.requests()[0].header("if-range"), Some("\"v1\""));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn completes_downloads_that_were_not_renamed() {
+        let dir = test_dir("complete");
+        let body = contents(64 * 1024);
+        write_partial(&dir, &body, "\"v1\"", body.len());
+        let served = body.clone();
+        let server = TestServer::start(move |req| Response::file(req, &served, "\"v1\"")).await;
+        let mut tasks = Tasks::hidden();
+        let t = tasks.new_task("test").unwrap();
+
+        let url = format!("{}/file.tar.gz", server.url);
+        let downloaded = fetch(&t, &url, &dir).await.unwrap();
+        assert_eq!(std::fs::read(downloaded.path).unwrap(), body);
+        assert!(!dir.join("file.tar.gz.part").exists());
+        assert!(!dir.join("file.tar.gz.part.json").exists());
+        assert_eq!(server.requests().len(), 1);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn completes_chunked_downloads_that_were_not_renamed() {
+        let dir = test_dir("complete-chunks");
+        let body = contents(64 * 1024);
+        std::fs::write(dir.join("file.tar.gz.part"), &body).unwrap();
+        let half = body.len() as u64 / 2;
+        let partial = PartialDownload {
+            url: None,
+            etag: Some("\"v1\"".into()),
+            last_modified: None,
+            content_length: Some(body.len() as u64),
+            chunks: vec![
+                ChunkProgress {
+                    start: 0,
+                    end: half,
+                    written: half,
+                },
+                ChunkProgress {
+                    start: half,
+                    end: body.len() as u64,
+                    written: body.len() as u64 - half,
+                },
+            ],
+        };
+        std::fs::write(
+            dir.join("file.tar.gz.part.json"),
+            serde_json::to_string(&partial).unwrap(),
+        )
+        .unwrap();
+        let server = TestServer::start(|_| Response::new(500)).await;
+        let mut tasks = Tasks::hidden();
+        let t = tasks.new_task("test").unwrap();
+
+        let url = format!("{}/file.tar.gz", server.url);
+        let downloaded = fetch(&t, &url, &dir).await.unwrap();
+        assert_eq!(std::fs::read(downloaded.path).unwrap(), body);
+        assert!(!dir.join("file.tar.gz.part.json").exists());
+        assert!(server.requests().is_empty());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn rejects_unexpected_content_ranges() {
+        let dir = test_dir("content-range");
+        let body = contents(64 * 1024);
+        write_partial(&dir, &body[..1000], "\"v1\"", body.len());
+        let served = body.clone();
+        let server = TestServer::start(move |_| {
+            // Starts over instead of where the partial download stopped
+            let content_range = format!("bytes 0-{}/{}", served.len() - 1, served.len());
+            Response::new(206)
+                .header("Content-Range", content_range)
+                .body(&served)
+        })
+        .await;
+        let mut tasks = Tasks::hidden();
+        let t = tasks.new_task("test").unwrap();
+
+        let url = format!("{}/file.tar.gz", server.url);
+        let result = fetch(&t, &url, &dir).await;
+        assert!(matches!(result, Err(DownloadError::ContentRange(_))));
+        assert!(!dir.join("file.tar.gz").exists());
+        assert!(!dir.join("file.tar.gz.part").exists());
+        assert!(!dir.join("file.tar.gz.part.json").exists());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    fn mirror_urls(env: Option<&str>, mirrors: &[&str], template: Option<&str>) -> Vec<String> {
+        let mirrors = mirrors.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+        let url = "https://github.com/llvm/llvm-project/releases/download/llvmorg-17.0.6/a.tar.xz";
+        Mirrors::with_env(env.map(str::to_string), &mirrors, template).urls(url, &version("17.0.6"))
+    }
+
+    #[test]
+    fn expands_mirror_templates() {
+        let original =
+            "https://github.com/llvm/llvm-project/releases/download/llvmorg-17.0.6/a.tar.xz";
+        assert_eq!(mirror_urls(None, &[], None), [original]);
+        assert_eq!(
+            mirror_urls(None, &["https://a.test/llvm/", "https://b.test"], None),
+            [
+                "https://a.test/llvm/llvmorg-17.0.6/a.tar.xz",
+                "https://b.test/llvmorg-17.0.6/a.tar.xz",
+                original,
+            ]
+        );
+        assert_eq!(
+            mirror_urls(
+                None,
+                &["https://a.test", "https://b.test/{file}"],
+                Some("{base}/{version}/files/{file}")
+            ),
+            [
+                "https://a.test/17.0.6/files/a.tar.xz",
+                "https://b.test/a.tar.xz",
+                original,
+            ]
+        );
+    }
+
+    #[test]
+    fn prefers_mirrors_from_the_environment() {
+        let original =
+            "https://github.com/llvm/llvm-project/releases/download/llvmorg-17.0.6/a.tar.xz";
+        assert_eq!(
+            mirror_urls(
+                Some(" https://env.test/, ,https://env.test/{version}/{file}"),
+                &["https://a.test"],
+                None
+            ),
+            [
+                "https://env.test/llvmorg-17.0.6/a.tar.xz",
+                "https://env.test/17.0.6/a.tar.xz",
+                original,
+            ]
+        );
+        assert_eq!(mirror_urls(Some(""), &["https://a.test"], None), [original]);
+
+        let mirrors = Mirrors::with_env(
+            Some("https://env.test:8080/{file}".into()),
+            &["https://a.test".into()],
+            None,
+        );
+        assert_eq!(mirrors.hosts(), ["env.test"]);
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_the_next_mirror() {
+        let dir = test_dir("mirrors");
+        let failing = TestServer::start(|_| Response::new(500)).await;
+        let body = contents(1024);
+        let served = body.clone();
+        let server = TestServer::start(move |req| Response::file(req, &served, "\"v1\"")).await;
+        let urls = [
+            format!("{}/file.tar.gz", refused_url().await),
+            format!("{}/file.tar.gz", failing.url),
+            format!("{}/file.tar.gz", server.url),
+        ];
+        let mut tasks = Tasks::hidden();
+        let t = tasks.new_task("test").unwrap();
+
+        let downloaded = download(&t, &urls, None, &dir).await.unwrap();
+        assert_eq!(std::fs::read(downloaded.path).unwrap(), body);
+        assert_eq!(failing.requests().len(), 1);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn keeps_downloads_of_other_servers() {
+        let dir = test_dir("mirror-partial");
+        let missing = TestServer::start(|_| Response::new(404)).await;
+        let body = contents(256 * 1024);
+        let served = body.clone();
+        let server = TestServer::start(move |req| {
+            let res = Response::file(req, &served, "\"v1\"");
+            if req.header("range").is_none() {
+                res.cut(served.len() / 2)
+            } else {
+                res
+            }
+        })
+        .await;
+        let urls = [
+            format!("{}/file.tar.gz", missing.url),
+            format!("{}/file.tar.gz", server.url),
+        ];
+        let mut tasks = Tasks::hidden();
+        let t = tasks.new_task("test").unwrap();
+
+        assert!(download(&t, &urls, None, &dir).await.is_err());
+        let part = std::fs::metadata(dir.join("file.tar.gz.part"))
+            .unwrap()
+            .len();
+        assert!(part > 0 && part < body.len() as u64);
+
+        let downloaded = download(&t, &urls, None, &dir).await.unwrap();
+        assert_eq!(std::fs::read(downloaded.path).unwrap(), body);
+        assert_eq!(missing.requests().len(), 2);
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(
+            requests[1].header("range"),
+            Some(format!("bytes={part}-").as_str())
+        );
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn reports_every_failed_mirror() {
+        let dir = test_dir("failed-mirrors");
+        let failing = TestServer::start(|_| Response::new(500)).await;
+        let urls = [
+            format!("{}/file.tar.gz", refused_url().await),
+            format!("{}/file.tar.gz", failing.url),
+        ];
+        let mut tasks = Tasks::hidden();
+        let t = tasks.new_task("test").unwrap();
+
+        let result = download(&t, &urls, None, &dir).await;
+        let Err(DownloadError::Mirrors(errors)) = result else {
+            panic!("expected every mirror to fail");
+        };
+        assert!(matches!(errors[0].1, DownloadError::Reqwest(_)));
+        assert!(matches!(
+            errors[1].1,
+            DownloadError::Http(reqwest::StatusCode::INTERNAL_SERVER_ERROR)
+        ));
+        assert!(!dir.join("file.tar.gz").exists());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    fn sha256(bytes: &[u8]) -> String {
+        use sha2::Digest;
+        format!("{:x}", sha2::Sha256::digest(bytes))
+    }
+
+    #[tokio::test]
+    async fn verifies_sha256() {
+        let dir = test_dir("sha256");
+        let path = dir.join("file.tar.gz");
+        std::fs::write(&path, b"contents").unwrap();
+        let mut tasks = Tasks::hidden();
+        let t = tasks.new_task("test").unwrap();
+
+        let digest = sha256(b"contents");
+        assert!(verify_sha256(&t, &path, &digest).is_ok());
+        assert!(verify_sha256(&t, &path, &format!(" {} ", digest.to_uppercase())).is_ok());
+        let result = verify_sha256(&t, &path, &sha256(b"other contents"));
+        assert!(matches!(
+            result,
+            Err(DownloadError::ChecksumMismatch(_, _, actual)) if actual == digest
+        ));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn quarantines_downloads_with_another_sha256() {
+        let dir = test_dir("quarantine");
+        let body = contents(1024);
+        let served = body.clone();
+        let server = TestServer::start(move |req| Response::file(req, &served, "\"v1\"")).await;
+        let urls = [format!("{}/file.tar.gz", server.url)];
+        let mut tasks = Tasks::hidden();
+        let t = tasks.new_task("test").unwrap();
+
+        let result = download(&t, &urls, Some(&sha256(b"other contents")), &dir).await;
+        let Err(DownloadError::ChecksumMismatch(path, _, _)) = result else {
+            panic!("expected a checksum mismatch");
+        };
+        assert_eq!(path, dir.join("quarantine").join("file.tar.gz"));
+        assert_eq!(std::fs::read(path).unwrap(), body);
+        assert!(!dir.join("file.tar.gz").exists());
+
+        // Downloaded again rather than reused
+        let downloaded = download(&t, &urls, Some(&sha256(&body)), &dir)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(downloaded.path).unwrap(), body);
+        assert_eq!(server.requests().len(), 2);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
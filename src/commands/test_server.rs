@@ -0,0 +1,177 @@
+//! A local HTTP server standing in for GitHub and the mirrors in tests.
+
+use super::{config::Config, http::init_http_client};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, MutexGuard},
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+
+pub(crate) struct Request {
+    pub path: String,
+    /// By lowercase name
+    pub headers: HashMap<String, String>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(String::as_str)
+    }
+}
+
+pub(crate) struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    /// Bytes of the body sent before the connection is closed
+    cut: Option<usize>,
+}
+
+impl Response {
+    pub fn new(status: u16) -> Response {
+        Response {
+            status,
+            headers: vec![],
+            body: vec![],
+            cut: None,
+        }
+    }
+
+    pub fn header(mut self, name: &str, value: impl ToString) -> Response {
+        self.headers.push((name.into(), value.to_string()));
+        self
+    }
+
+    pub fn body(mut self, body: &[u8]) -> Response {
+        self.body = body.to_vec();
+        self
+    }
+
+    /// Closes the connection after `n` bytes of the body, as if it was interrupted.
+    pub fn cut(mut self, n: usize) -> Response {
+        self.cut = Some(n);
+        self
+    }
+
+    /// `body` as sent by a server that accepts ranges of it, when `etag` matches
+    /// their `If-Range`.
+    pub fn file(req: &Request, body: &[u8], etag: &str) -> Response {
+        let len = body.len();
+        let range = req
+            .header("range")
+            .and_then(|range| range.strip_prefix("bytes="))
+            .and_then(|range| range.split_once('-'))
+            .filter(|_| {
+                req.header("if-range")
+                    .is_none_or(|validator| validator == etag)
+            });
+        let Some((start, end)) = range else {
+            return Response::new(200)
+                .header("ETag", etag)
+                .header("Accept-Ranges", "bytes")
+                .body(body);
+        };
+
+        let start: usize = start.parse().unwrap();
+        let end = end.parse().unwrap_or(len - 1).min(len - 1);
+        if start >= len {
+            return Response::new(416).header("Content-Range", format!("bytes */{len}"));
+        }
+        Response::new(206)
+            .header("ETag", etag)
+            .header("Accept-Ranges", "bytes")
+            .header("Content-Range", format!("bytes {start}-{end}/{len}"))
+            .body(&body[start..=end])
+    }
+}
+
+pub(crate) struct TestServer {
+    pub url: String,
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl TestServer {
+    /// Answers every request with `handler`, one request per connection.
+    ///
+    /// Downloads of tests go through a client of the default configuration, not the
+    /// one of the machine they run on.
+    pub async fn start(handler: impl Fn(&Request) -> Response + Send + Sync + 'static) -> Self {
+        init_http_client(&Config::default()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(vec![]));
+
+        let handler = Arc::new(handler);
+        let server_requests = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let handler = handler.clone();
+                let requests = server_requests.clone();
+                tokio::spawn(async move {
+                    let _ = serve(stream, handler.as_ref(), &requests).await;
+                });
+            }
+        });
+
+        TestServer { url, requests }
+    }
+
+    /// Requests received so far, in order.
+    pub fn requests(&self) -> MutexGuard<'_, Vec<Request>> {
+        self.requests.lock().unwrap()
+    }
+}
+
+/// Url of a port nothing listens on.
+pub(crate) async fn refused_url() -> String {
+    init_http_client(&Config::default()).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    format!("http://{}", listener.local_addr().unwrap())
+}
+
+async fn serve(
+    stream: TcpStream,
+    handler: &(impl Fn(&Request) -> Response + ?Sized),
+    requests: &Mutex<Vec<Request>>,
+) -> std::io::Result<()> {
+    let mut stream = BufReader::new(stream);
+
+    let mut line = String::new();
+    stream.read_line(&mut line).await?;
+    let path = line
+        .split_whitespace()
+        .nth(1)
+        .unwrap_or_default()
+        .to_string();
+    let mut headers = HashMap::new();
+    loop {
+        line.clear();
+        stream.read_line(&mut line).await?;
+        let Some((name, value)) = line.trim_end().split_once(':') else {
+            break;
+        };
+        headers.insert(name.to_ascii_lowercase(), value.trim().to_string());
+    }
+
+    let req = Request { path, headers };
+    let res = handler(&req);
+    requests.lock().unwrap().push(req);
+
+    let mut head = format!("HTTP/1.1 {} Test\r\n", res.status);
+    for (name, value) in res.headers.iter() {
+        head += &format!("{name}: {value}\r\n");
+    }
+    head += &format!(
+        "Content-Length: {}\r\nConnection: close\r\n\r\n",
+        res.body.len()
+    );
+
+    let stream = stream.get_mut();
+    stream.write_all(head.as_bytes()).await?;
+    let body = &res.body[..res.cut.unwrap_or(res.body.len())];
+    stream.write_all(body).await?;
+    stream.shutdown().await
+}
@@ -3,7 +3,8 @@ mod tasks;
 
 use argp::FromArgs;
 use color_eyre::{eyre::Report, eyre::WrapErr};
-use commands::read_shell;
+use commands::{read_install_metadata, read_shell, CompilerCache, Profile};
+use std::{path::PathBuf, str::FromStr};
 
 /// Install LLVM tools
 #[derive(FromArgs, PartialEq, Debug)]
@@ -13,9 +14,104 @@ struct InstallSubcommand {
     #[argp(positional)]
     name: String,
 
-    /// Options: 16, 17, 18
+    /// Options: 17, 17.0, 17.0.6, ^18, ~17.0, ">=17, <19", latest
     #[argp(positional)]
-    version: String,
+    version: Option<String>,
+
+    /// Install official prebuilt binaries, if available for this host, instead of
+    /// compiling from source.
+    #[argp(switch)]
+    prebuilt: bool,
+
+    /// Build from a local source archive (tar.xz, tar.gz, tar.zst, tar.bz2 or zip) instead
+    /// of downloading it.
+    #[argp(option, arg_name = "path")]
+    from_archive: Option<PathBuf>,
+
+    /// Build from a local source tree instead of downloading it.
+    #[argp(option, arg_name = "dir")]
+    from_source: Option<PathBuf>,
+
+    /// Build a revision of a local llvm-project clone.
+    #[argp(option, arg_name = "repo")]
+    git: Option<PathBuf>,
+
+    /// Revision to build with --git. Defaults to HEAD.
+    #[argp(option, arg_name = "sha|branch|tag")]
+    rev: Option<String>,
+
+    /// Extra argument for the cmake configure step, e.g. --cmake-arg -DFOO=BAR.
+    /// Can be repeated.
+    #[argp(option, arg_name = "arg")]
+    cmake_arg: Vec<String>,
+
+    /// Reuse the cmake arguments recorded by the previous install of this version.
+    #[argp(switch)]
+    rebuild: bool,
+
+    /// Options: release, debug, relwithdebinfo. Defaults to release.
+    #[argp(option, default = "Profile::Release", from_str_fn(Profile::from_str))]
+    profile: Profile,
+
+    /// Build with LLVM_ENABLE_ASSERTIONS=ON.
+    #[argp(switch)]
+    assertions: bool,
+
+    /// LLVM projects to build, e.g. clang,lld,mlir, or none. Defaults to clang,lld.
+    #[argp(option, arg_name = "list")]
+    projects: Option<String>,
+
+    /// LLVM runtimes to build, e.g. compiler-rt,libcxx. Defaults to none.
+    #[argp(option, arg_name = "list")]
+    runtimes: Option<String>,
+
+    /// LLVM backends to build, e.g. host,AArch64,WebAssembly. Defaults to all.
+    #[argp(option, arg_name = "list")]
+    targets: Option<String>,
+
+    /// Experimental LLVM backends to build, e.g. SPIRV.
+    #[argp(option, arg_name = "list")]
+    experimental_targets: Option<String>,
+
+    /// Parallel compile jobs. Defaults to the number of CPUs.
+    #[argp(option, short = 'j', arg_name = "N")]
+    jobs: Option<usize>,
+
+    /// Parallel link jobs. Defaults to what fits in memory.
+    #[argp(option, arg_name = "N")]
+    link_jobs: Option<usize>,
+
+    /// Options: auto, off, ccache, sccache. Defaults to auto.
+    #[argp(
+        option,
+        default = "CompilerCache::Auto",
+        from_str_fn(CompilerCache::from_str)
+    )]
+    compiler_cache: CompilerCache,
+
+    /// Build clang and lld first, then build LLVM with them.
+    #[argp(switch)]
+    bootstrap: bool,
+
+    /// Build LLVM with the clang and lld of an installed version, e.g. 17.0.6.
+    #[argp(option, arg_name = "version")]
+    host_toolchain: Option<String>,
+
+    /// Start from scratch instead of resuming an interrupted install.
+    #[argp(switch)]
+    clean: bool,
+
+    /// Expected SHA-256 digest of the downloaded or local archive.
+    #[argp(option, arg_name = "digest")]
+    sha256: Option<String>,
+
+    /// Fail unless archives are signed by a known LLVM release key.
+    #[argp(switch)]
+    require_signature: bool,
+
+    /// Parallel downloads. Defaults to 4.
+    #[argp(option, arg_name = "N")]
+    download_jobs: Option<usize>,
 }
 
 /// Setup shell environment variables
@@ -27,11 +123,18 @@ struct EnvSubcommand {
     shell: String,
 }
 
+/// List installed versions and how they were built
+#[derive(FromArgs, PartialEq, Debug)]
+#[argp(subcommand, name = "list")]
+struct ListSubcommand {}
+
 #[derive(FromArgs, PartialEq, Debug)]
 #[argp(subcommand)]
+#[allow(clippy::large_enum_variant)]
 enum Commands {
     Install(InstallSubcommand),
     Env(EnvSubcommand),
+    List(ListSubcommand),
 }
 
 /// LLVM Manager downloads, compiles and installs LLVM tools for you.
@@ -52,16 +155,27 @@ async fn main() -> Result<(), Report> {
     let args: Args = argp::parse_args_or_exit(argp::DEFAULT);
 
     match &args.command {
-        Commands::Install(cmd) => commands::install::run(&args, cmd)
-            .await
-            .wrap_err_with(|| format!("Unable to install {} {}", cmd.name, cmd.version)),
+        Commands::Install(cmd) => {
+            commands::install::run(&args, cmd)
+                .await
+                .wrap_err_with(|| match cmd.version.as_ref() {
+                    Some(version) => format!("Unable to install {} {version}", cmd.name),
+                    None => format!("Unable to install {}", cmd.name),
+                })
+        }
         Commands::Env(cmd) if cmd.shell == "bash" => {
             let shell = read_shell().wrap_err("Unable to read shell configuration")?;
             for (k, v) in shell.env_vars {
-                println!("export {k}={v}",);
+                match read_install_metadata(&v) {
+                    Ok(Some(metadata)) => println!("export {k}={v} # {}", metadata.variant()),
+                    _ => println!("export {k}={v}",),
+                }
             }
             Ok(())
         }
+        Commands::List(cmd) => {
+            commands::list::run(&args, cmd).wrap_err("Unable to list installed versions")
+        }
         _ => todo!(),
     }
 }
@@ -1,4 +1,8 @@
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
 use thiserror::Error;
 
 #[derive(Clone)]
@@ -13,31 +17,55 @@ impl Drop for Tasks {
     }
 }
 
+#[derive(Clone)]
 pub struct TaskRef {
     id: usize,
     sender: flume::Sender<Messages>,
+    /// Position of the bar, in percent, so it is only sent when it moves
+    percentage: Arc<AtomicU64>,
 }
 
 impl TaskRef {
     pub fn set_subtask(&self, subtask: &str) {
+        self.percentage.store(0, Ordering::Relaxed);
         self.sender
             .send(Messages::SetSubtask(self.id, subtask.into(), None))
             .unwrap();
     }
 
     pub fn set_subtask_with_percentage(&self, subtask: &str, p: f64) {
+        self.percentage.store((p * 100.0) as u64, Ordering::Relaxed);
         self.sender
             .send(Messages::SetSubtask(self.id, subtask.into(), Some(p)))
             .unwrap();
     }
 
     pub fn finish(&self) {
-        self.sender.send(Messages::Finish(self.id)).unwrap();
+        self.sender.send(Messages::Finish(self.id, None)).unwrap();
     }
 
+    /// Finishes the task, keeping `subtask` visible as a summary.
+    pub fn finish_with_subtask(&self, subtask: &str) {
+        self.sender
+            .send(Messages::Finish(self.id, Some(subtask.into())))
+            .unwrap();
+    }
+
+    /// Readers report every read, often of a few bytes, so only changes of the
+    /// integer percentage are sent.
     pub fn set_percentage(&self, p: f64) {
+        let percentage = (p * 100.0) as u64;
+        if self.percentage.swap(percentage, Ordering::Relaxed) != percentage {
+            self.sender
+                .send(Messages::SetPercentage(self.id, p))
+                .unwrap();
+        }
+    }
+
+    /// Prints `warning` above the progress bars.
+    pub fn warn(&self, warning: &str) {
         self.sender
-            .send(Messages::SetPercentage(self.id, p))
+            .send(Messages::Warn(self.id, warning.into()))
             .unwrap();
     }
 }
@@ -77,19 +105,20 @@ impl Task {
 pub enum Messages {
     NewTask { name: String },
     SetSubtask(usize, String, Option<f64>),
-    Finish(usize),
+    Finish(usize, Option<String>),
     SetPercentage(usize, f64), // between 0 and 1,
+    Warn(usize, String),
     Kill,
 }
 
-async fn tick_progress_bars(r: flume::Receiver<Messages>) {
+async fn tick_progress_bars(r: flume::Receiver<Messages>, target: ProgressDrawTarget) {
     let (w, _) = term_size::dimensions().unwrap_or((80, 0));
 
     let msg_width = w - 55;
     let template =
         format!("{{prefix:.bold.dim}} {{spinner}} {{msg:{msg_width}}} {{bar:40}} {{eta}}");
 
-    let m = MultiProgress::new();
+    let m = MultiProgress::with_draw_target(target);
     let style = ProgressStyle::with_template(&template)
         .expect("should not fail")
         .tick_chars("⠁⠂⠄⡀⢀⠠⠐⠈ ");
@@ -116,14 +145,23 @@ async fn tick_progress_bars(r: flume::Receiver<Messages>) {
                         tasks[i].pb.set_position((p.unwrap_or_default() * 100.0) as u64);
                         tasks[i].update(i, tasks.len());
                     }
-                    Ok(Messages::Finish(i)) => {
-                        tasks[i].subtask = None;
+                    Ok(Messages::Finish(i, subtask)) => {
+                        tasks[i].subtask = subtask;
                         tasks[i].pb.finish();
                         tasks[i].update(i, tasks.len());
                     }
                     Ok(Messages::SetPercentage(i, p)) => {
                         tasks[i].pb.set_position((p * 100.0) as u64);
                     }
+                    Ok(Messages::Warn(i, warning)) => {
+                        let warning = format!("warning: {}: {warning}", tasks[i].name);
+                        // Without a terminal, nothing is drawn above the bars
+                        if m.is_hidden() {
+                            eprintln!("{warning}");
+                        } else {
+                            let _ = m.println(warning);
+                        }
+                    }
                     Ok(Messages::Kill) | Err(_) => break
                 }
             }
@@ -145,8 +183,18 @@ pub enum TaskErrors {
 
 impl Tasks {
     pub fn new() -> Tasks {
+        Tasks::with_draw_target(ProgressDrawTarget::stderr())
+    }
+
+    /// Tasks that do not draw progress bars, for tests.
+    #[cfg(test)]
+    pub fn hidden() -> Tasks {
+        Tasks::with_draw_target(ProgressDrawTarget::hidden())
+    }
+
+    fn with_draw_target(target: ProgressDrawTarget) -> Tasks {
         let (sender, r) = flume::unbounded();
-        tokio::spawn(tick_progress_bars(r));
+        tokio::spawn(tick_progress_bars(r, target));
         Tasks { id: 0, sender }
     }
 
@@ -161,6 +209,7 @@ impl Tasks {
         Ok(TaskRef {
             id,
             sender: self.sender.clone(),
+            percentage: Arc::new(AtomicU64::new(0)),
         })
     }
 }